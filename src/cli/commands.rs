@@ -1,100 +1,680 @@
-use anyhow::Result;
+use std::io::{self, Write};
+use std::path::Path;
+use std::pin::pin;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
 use chrono::SecondsFormat;
-use log::info;
+use futures::{Stream, StreamExt, TryStreamExt};
+use log::{info, warn};
+use serde::Serialize;
 
-use crate::s3::client::S3Client;
+use super::output::{format_date, OutputFormat, Printer, Record};
+use super::url::S3Url;
+use crate::config::app_config::AppConfig;
+use crate::s3::client::{
+    BucketInfo, CopyOptions, DeletedObject, Direction, HeadObjectInfo, ListEntry, ObjectInfo,
+    S3Client, SyncOptions, TransferOptions, TransferSummary,
+};
+use crate::s3::sync::SyncPlan;
+use crate::utils::format_size;
 
 pub enum Command {
     ListBuckets,
     CreateBucket {
-        name: String,
+        url: S3Url,
     },
     DeleteBucket {
-        name: String,
+        url: S3Url,
+        /// Empty the bucket before deleting it.
+        force: bool,
+        /// Skip the confirmation prompt for `force`.
+        yes: bool,
+        concurrency: usize,
     },
     ListObjects {
-        bucket: String,
-        prefix: Option<String>,
+        /// The key of the URL is used as the listing prefix.
+        url: S3Url,
+        /// `None` lists every key flat.
+        delimiter: Option<String>,
+        long: bool,
+        human_readable: bool,
     },
     UploadObject {
-        bucket: String,
-        key: String,
         file_path: String,
+        /// The object, or the key prefix when `recursive` is set.
+        url: S3Url,
+        recursive: bool,
+        options: TransferOptions,
     },
     DownloadObject {
-        bucket: String,
-        key: String,
+        /// The object, or the key prefix when `recursive` is set.
+        url: S3Url,
         file_path: String,
+        recursive: bool,
+        options: TransferOptions,
+    },
+    CopyObject {
+        /// The object, or the key prefix when `recursive` is set.
+        src: S3Url,
+        dst: S3Url,
+        recursive: bool,
+        options: CopyOptions,
+    },
+    MoveObject {
+        /// The object, or the key prefix when `recursive` is set.
+        src: S3Url,
+        dst: S3Url,
+        recursive: bool,
+        options: CopyOptions,
+    },
+    Sync {
+        /// `Upload` syncs `dir` to `url`, `Download` the reverse.
+        direction: Direction,
+        dir: String,
+        url: S3Url,
+        options: SyncOptions,
+        /// Print the plan instead of carrying it out.
+        dry_run: bool,
+    },
+    /// Mirrors `dir` to `url` until interrupted.
+    WatchSync {
+        dir: String,
+        url: S3Url,
+        options: SyncOptions,
+        /// How long a file must go unchanged before it is uploaded.
+        settle: Duration,
+    },
+    SyncBuckets {
+        /// Either URL may start with an alias from the config's `[aliases]`.
+        src: S3Url,
+        dst: S3Url,
+        options: SyncOptions,
+        dry_run: bool,
     },
     DeleteObject {
-        bucket: String,
-        key: String,
+        /// The object, or the key prefix when `recursive` is set.
+        url: S3Url,
+        recursive: bool,
+        /// Number of DeleteObjects batches in flight when `recursive` is set.
+        concurrency: usize,
+    },
+    HeadObject {
+        url: S3Url,
     },
 }
 
+/// Result of a command that has nothing to report beyond success.
+#[derive(Serialize)]
+struct ActionResult<'a> {
+    action: &'static str,
+    bucket: &'a str,
+    key: Option<&'a str>,
+}
+
+impl Record for ActionResult<'_> {
+    fn headers() -> &'static [&'static str] {
+        &["action", "bucket", "key"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.action.to_string(),
+            self.bucket.to_string(),
+            self.key.unwrap_or_default().to_string(),
+        ]
+    }
+}
+
 impl Command {
-    pub async fn execute(&self, client: &S3Client) -> Result<()> {
+    pub async fn execute(
+        &self,
+        config: &AppConfig,
+        client: &S3Client,
+        output: OutputFormat,
+    ) -> Result<()> {
         match self {
             Command::ListBuckets => {
-                let buckets = client.list_buckets().await?;
-                for bucket in buckets {
-                    println!("{:30}   {}",
-                             bucket.name,
-                             bucket.creation_date
-                                 .map(|d| d.to_rfc3339_opts(SecondsFormat::Secs, true))
-                                 .unwrap_or_else(|| "Unknown".to_string()))
+                let mut printer = Printer::new(output, |bucket: &BucketInfo| {
+                    format!(
+                        "{:30}   {}",
+                        bucket.name,
+                        bucket
+                            .creation_date
+                            .map(|d| d.to_rfc3339_opts(SecondsFormat::Secs, true))
+                            .unwrap_or_else(|| "Unknown".to_string())
+                    )
+                });
+                for bucket in client.list_buckets().await? {
+                    printer.print(&bucket)?;
                 }
+                printer.finish()?;
             }
-            Command::CreateBucket { name } => {
+            Command::CreateBucket { url } => {
+                let name = &url.bucket;
                 info!("Creating bucket: {}", name);
                 client.create_bucket(name).await?;
-                println!("Bucket '{}' created successfully", name);
+                Printer::new(output, |r: &ActionResult| {
+                    format!("Bucket '{}' created successfully", r.bucket)
+                })
+                .print_one(&ActionResult {
+                    action: "create-bucket",
+                    bucket: name,
+                    key: None,
+                })?;
             }
-            Command::DeleteBucket { name } => {
+            Command::DeleteBucket {
+                url,
+                force,
+                yes,
+                concurrency,
+            } => {
+                let name = &url.bucket;
+                if *force {
+                    empty_bucket(client, name, *yes, *concurrency).await?;
+                }
                 info!("Deleting bucket: {}", name);
                 client.delete_bucket(name).await?;
-                println!("Bucket '{}' deleted successfully", name);
+                Printer::new(output, |r: &ActionResult| {
+                    format!("Bucket '{}' deleted successfully", r.bucket)
+                })
+                .print_one(&ActionResult {
+                    action: "delete-bucket",
+                    bucket: name,
+                    key: None,
+                })?;
             }
-            Command::ListObjects { bucket, prefix } => {
-                info!("Listing objects in bucket: {}", bucket);
-                let objects = client.list_objects(bucket, prefix.as_deref()).await?;
-                for object in objects {
-                    println!("{}", object);
+            Command::ListObjects {
+                url,
+                delimiter,
+                long,
+                human_readable,
+            } => {
+                info!("Listing objects in bucket: {}", url.bucket);
+                let prefix = (!url.key.is_empty()).then_some(url.key.as_str());
+                let mut entries = pin!(client.list_objects(
+                    &url.bucket,
+                    prefix,
+                    delimiter.as_deref()
+                ));
+                let hierarchical = delimiter.is_some();
+                let mut printer = Printer::new(output, |entry: &ListEntry| {
+                    format_entry(entry, *long, *human_readable, hierarchical)
+                });
+                let (mut total_objects, mut total_size) = (0u64, 0u64);
+                while let Some(entry) = entries.try_next().await? {
+                    if let ListEntry::Object(object) = &entry {
+                        total_objects += 1;
+                        total_size += object.size;
+                    }
+                    printer.print(&entry)?;
+                }
+                let text = printer.format() == OutputFormat::Text;
+                printer.finish()?;
+                if *long && text {
+                    println!();
+                    println!("Total Objects: {}", total_objects);
+                    println!("   Total Size: {}", display_size(total_size, *human_readable));
                 }
             }
             Command::UploadObject {
-                bucket,
-                key,
                 file_path,
+                url,
+                recursive: true,
+                options,
             } => {
-                info!("Uploading object to bucket: {}", bucket);
-                client.upload_object(bucket, key, file_path).await?;
-                println!(
-                    "Object '{}' uploaded successfully to bucket '{}'",
-                    key, bucket
-                );
+                info!("Uploading {} to {}", file_path, url);
+                let results =
+                    client.upload_dir(&url.bucket, &url.key, Path::new(file_path), options)?;
+                print_transfers(output, results).await?;
+            }
+            Command::UploadObject {
+                file_path,
+                url,
+                recursive: false,
+                options,
+            } => {
+                info!("Uploading object to bucket: {}", url.bucket);
+                let key = upload_key(url, file_path)?;
+                let summary = client
+                    .upload_object(&url.bucket, &key, file_path, options)
+                    .await?;
+                Printer::new(output, |s: &TransferSummary| {
+                    format!(
+                        "Object '{}' uploaded successfully to bucket '{}'",
+                        s.key, s.bucket
+                    )
+                })
+                .print_one(&summary)?;
+            }
+            Command::DownloadObject {
+                url,
+                file_path,
+                recursive: true,
+                options,
+            } => {
+                info!("Downloading {} to {}", url, file_path);
+                let results =
+                    client.download_prefix(&url.bucket, &url.key, Path::new(file_path), options);
+                print_transfers(output, results).await?;
             }
             Command::DownloadObject {
-                bucket,
-                key,
+                url,
                 file_path,
+                recursive: false,
+                options,
             } => {
-                info!("Downloading object from bucket: {}", bucket);
-                client.download_object(bucket, key, file_path).await?;
-                println!(
-                    "Object '{}' downloaded successfully from bucket '{}'",
-                    key, bucket
-                );
-            }
-            Command::DeleteObject { bucket, key } => {
+                info!("Downloading object from bucket: {}", url.bucket);
+                let file_path = download_path(url, file_path);
+                if let Some(parent) = Path::new(&file_path).parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let summary = client
+                    .download_object(&url.bucket, &url.key, &file_path, options)
+                    .await?;
+                Printer::new(output, |s: &TransferSummary| {
+                    format!(
+                        "Object '{}' downloaded successfully from bucket '{}'",
+                        s.key, s.bucket
+                    )
+                })
+                .print_one(&summary)?;
+            }
+            Command::CopyObject {
+                src,
+                dst,
+                recursive: true,
+                options,
+            } => {
+                info!("Copying {} to {}", src, dst);
+                let results =
+                    client.copy_prefix(&src.bucket, &src.key, &dst.bucket, &dst.key, options)?;
+                print_transfers(output, results).await?;
+            }
+            Command::CopyObject {
+                src,
+                dst,
+                recursive: false,
+                options,
+            } => {
+                info!("Copying {} to {}", src, dst);
+                let key = copy_key(src, dst);
+                let summary = client
+                    .copy_object(&src.bucket, &src.key, &dst.bucket, &key, options)
+                    .await?;
+                Printer::new(output, format_transfer).print_one(&summary)?;
+            }
+            Command::MoveObject {
+                src,
+                dst,
+                recursive: true,
+                options,
+            } => {
+                info!("Moving {} to {}", src, dst);
+                let results =
+                    client.move_prefix(&src.bucket, &src.key, &dst.bucket, &dst.key, options)?;
+                print_transfers(output, results).await?;
+            }
+            Command::MoveObject {
+                src,
+                dst,
+                recursive: false,
+                options,
+            } => {
+                info!("Moving {} to {}", src, dst);
+                let key = copy_key(src, dst);
+                let summary = client
+                    .move_object(&src.bucket, &src.key, &dst.bucket, &key, options)
+                    .await?;
+                Printer::new(output, format_transfer).print_one(&summary)?;
+            }
+            Command::Sync {
+                direction,
+                dir,
+                url,
+                options,
+                dry_run,
+            } => {
+                info!("Planning sync between {} and {}", dir, url);
+                let plan = client
+                    .plan_sync(*direction, Path::new(dir), &url.bucket, &url.key, options)
+                    .await?;
+                if *dry_run {
+                    print_plan(output, plan)?;
+                } else {
+                    print_transfers(output, client.run_sync(plan, options)).await?;
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Command::WatchSync {
+                dir,
+                url,
+                options,
+                settle,
+            } => {
+                info!("Syncing {} to {} as it changes", dir, url);
+                let results =
+                    client.watch_sync(Path::new(dir), &url.bucket, &url.key, options, *settle);
+                let mut results = pin!(results);
+                let mut printer = Printer::new(output, format_transfer);
+                while let Some(summary) = results.try_next().await? {
+                    printer.print(&summary)?;
+                }
+                printer.finish()?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            Command::WatchSync { .. } => bail!("sync --watch relies on inotify and needs Linux"),
+            Command::SyncBuckets {
+                src,
+                dst,
+                options,
+                dry_run,
+            } => {
+                let (src_alias, src) = resolve_alias(config, src)?;
+                let (dst_alias, dst) = resolve_alias(config, dst)?;
+                let target = client_for(config, client, dst_alias.as_deref()).await?;
+                // Buckets behind the same endpoint are copied server-side.
+                let source = if src_alias == dst_alias {
+                    None
+                } else {
+                    Some(client_for(config, client, src_alias.as_deref()).await?)
+                };
+                info!("Planning sync from {} to {}", src, dst);
+                let plan = target
+                    .plan_bucket_sync(
+                        source.as_ref(),
+                        &src.bucket,
+                        &src.key,
+                        &dst.bucket,
+                        &dst.key,
+                        options,
+                    )
+                    .await?;
+                if *dry_run {
+                    print_plan(output, plan)?;
+                } else {
+                    print_transfers(output, target.run_sync(plan, options)).await?;
+                }
+            }
+            Command::DeleteObject {
+                url,
+                recursive: true,
+                concurrency,
+            } => {
+                info!("Deleting objects under {}", url);
+                let results = client.delete_prefix(&url.bucket, &url.key, *concurrency);
+                print_deletes(output, results).await?;
+            }
+            Command::DeleteObject { url, .. } => {
+                let (bucket, key) = (&url.bucket, &url.key);
                 info!("Deleting object from bucket: {}", bucket);
                 client.delete_object(bucket, key).await?;
-                println!(
-                    "Object '{}' deleted successfully from bucket '{}'",
-                    key, bucket
-                );
+                Printer::new(output, |r: &ActionResult| {
+                    format!(
+                        "Object '{}' deleted successfully from bucket '{}'",
+                        r.key.unwrap_or_default(),
+                        r.bucket
+                    )
+                })
+                .print_one(&ActionResult {
+                    action: "delete-object",
+                    bucket,
+                    key: Some(key),
+                })?;
+            }
+            Command::HeadObject { url } => {
+                let head = client.head_object(&url.bucket, &url.key).await?;
+                Printer::new(output, format_head).print_one(&head)?;
             }
         }
         Ok(())
     }
 }
+
+/// The key to upload `file_path` to: a URL ending in `/` (or naming just the
+/// bucket) gets the file name appended, like `cp file dir/`.
+fn upload_key(url: &S3Url, file_path: &str) -> Result<String> {
+    if !url.is_dir() {
+        return Ok(url.key.clone());
+    }
+    match Path::new(file_path).file_name() {
+        Some(name) => Ok(format!("{}{}", url.key, name.to_string_lossy())),
+        None => bail!("Cannot derive an object key from '{}'", file_path),
+    }
+}
+
+/// The destination key for copying `src` to `dst`: a `dst` ending in `/`
+/// (or naming just the bucket) gets the source's file name appended.
+fn copy_key(src: &S3Url, dst: &S3Url) -> String {
+    match src.file_name() {
+        Some(name) if dst.is_dir() => format!("{}{}", dst.key, name),
+        _ => dst.key.clone(),
+    }
+}
+
+/// The file to download `url` to: an existing directory, or a path ending in
+/// `/`, receives the object's file name.
+fn download_path(url: &S3Url, file_path: &str) -> String {
+    match url.file_name() {
+        Some(name) if file_path.ends_with('/') || Path::new(file_path).is_dir() => {
+            Path::new(file_path).join(name).to_string_lossy().into_owned()
+        }
+        _ => file_path.to_string(),
+    }
+}
+
+/// Prints the actions of a sync plan without carrying them out.
+fn print_plan(output: OutputFormat, plan: SyncPlan) -> Result<()> {
+    let mut printer = Printer::new(output, |s: &TransferSummary| {
+        format!("(dryrun) {}", format_transfer(s))
+    });
+    for summary in plan.summaries() {
+        printer.print(&summary)?;
+    }
+    printer.finish()
+}
+
+/// Splits `s3://<alias>/<bucket>/<key>` into the alias and the URL it
+/// addresses when the first segment names an alias from the config;
+/// otherwise the URL is returned unchanged.
+fn resolve_alias(config: &AppConfig, url: &S3Url) -> Result<(Option<String>, S3Url)> {
+    if !config.has_alias(&url.bucket) {
+        return Ok((None, url.clone()));
+    }
+    let target = format!("s3://{}", url.key)
+        .parse::<S3Url>()
+        .map_err(|e| anyhow!("{} (in {})", e, url))?;
+    Ok((Some(url.bucket.clone()), target))
+}
+
+/// The client for `alias`, or the default client without one.
+async fn client_for(
+    config: &AppConfig,
+    default: &S3Client,
+    alias: Option<&str>,
+) -> Result<S3Client> {
+    let settings = match alias {
+        Some(alias) => config.alias(alias)?,
+        None => None,
+    };
+    match settings {
+        Some(settings) => S3Client::new(&settings).await,
+        None => Ok(default.clone()),
+    }
+}
+
+/// Prints per-file results of a recursive transfer, failing if any file failed.
+async fn print_transfers(
+    output: OutputFormat,
+    results: impl Stream<Item = TransferSummary>,
+) -> Result<()> {
+    let mut printer = Printer::new(output, format_transfer);
+    let (mut total, mut failed) = (0, 0);
+    let mut results = pin!(results);
+    while let Some(summary) = results.next().await {
+        total += 1;
+        if summary.error.is_some() {
+            failed += 1;
+        }
+        printer.print(&summary)?;
+    }
+    printer.finish()?;
+    if failed > 0 {
+        bail!("{} of {} transfers failed", failed, total);
+    }
+    info!("{} files transferred", total);
+    Ok(())
+}
+
+/// Removes every object version, delete marker and multipart upload from
+/// `bucket`, asking for confirmation first unless `yes` is set.
+async fn empty_bucket(
+    client: &S3Client,
+    bucket: &str,
+    yes: bool,
+    concurrency: usize,
+) -> Result<()> {
+    if !yes {
+        // Counting means listing the whole bucket, so it is only worth it
+        // when someone reads the prompt.
+        let versions = client.count_versions(bucket).await?;
+        let uploads = client.list_multipart_uploads(bucket).await?.len();
+        let prompt = format!(
+            "Delete bucket '{}' with {} object versions and {} in-progress multipart uploads?",
+            bucket, versions, uploads
+        );
+        if !confirm(&prompt)? {
+            bail!("Aborted, bucket '{}' was not deleted", bucket);
+        }
+    }
+
+    info!("Emptying bucket: {}", bucket);
+    let mut results = pin!(client.empty_bucket(bucket, concurrency));
+    let (mut total, mut failed) = (0, 0);
+    while let Some(deleted) = results.next().await {
+        total += 1;
+        if deleted.error.is_some() {
+            failed += 1;
+            warn!("{}", format_delete(&deleted));
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} object versions could not be deleted", failed, total);
+    }
+    let aborted = client.abort_multipart_uploads(bucket, concurrency).await?;
+    info!("Deleted {} object versions and aborted {} multipart uploads", total, aborted);
+    Ok(())
+}
+
+/// Asks a yes/no question on the terminal; anything but "y" or "yes" declines.
+fn confirm(prompt: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Prints per-key results of a batch delete, failing if any key was not deleted.
+async fn print_deletes(
+    output: OutputFormat,
+    results: impl Stream<Item = DeletedObject>,
+) -> Result<()> {
+    let mut printer = Printer::new(output, format_delete);
+    let (mut total, mut failed) = (0, 0);
+    let mut results = pin!(results);
+    while let Some(deleted) = results.next().await {
+        total += 1;
+        if deleted.error.is_some() {
+            failed += 1;
+        }
+        printer.print(&deleted)?;
+    }
+    printer.finish()?;
+    if failed > 0 {
+        bail!("{} of {} deletes failed", failed, total);
+    }
+    info!("{} objects deleted", total);
+    Ok(())
+}
+
+fn format_delete(deleted: &DeletedObject) -> String {
+    let mut target = format!("s3://{}/{}", deleted.bucket, deleted.key);
+    if let Some(version_id) = &deleted.version_id {
+        target = format!("{} (version {})", target, version_id);
+    }
+    match &deleted.error {
+        Some(error) => format!("delete failed: {}: {}", target, error),
+        None => format!("delete: {}", target),
+    }
+}
+
+fn format_transfer(summary: &TransferSummary) -> String {
+    let action = summary.direction.as_str();
+    let remote = format!("s3://{}/{}", summary.bucket, summary.key);
+    let target = match summary.direction {
+        Direction::Upload | Direction::Copy | Direction::Move => {
+            format!("{} to {}", summary.path, remote)
+        }
+        Direction::Download => format!("{} to {}", remote, summary.path),
+        // Sync deletions name the local file, or the object when that is empty.
+        Direction::Delete if summary.path.is_empty() => remote,
+        Direction::Delete => summary.path.clone(),
+    };
+    match &summary.error {
+        Some(error) => format!("{} failed: {}: {}", action, target, error),
+        None => format!("{}: {}", action, target),
+    }
+}
+
+/// Keys are only indented to line up with `PRE` rows in a hierarchical
+/// listing; a flat one prints them bare, one per line, for piping.
+fn format_entry(entry: &ListEntry, long: bool, human_readable: bool, hierarchical: bool) -> String {
+    match entry {
+        ListEntry::Prefix { prefix } if long => format!("{:>20} {:>12} {}", "", "PRE", prefix),
+        ListEntry::Prefix { prefix } => format!("{:>10} {}", "PRE", prefix),
+        ListEntry::Object(object) if long => format_long(object, human_readable),
+        ListEntry::Object(object) if hierarchical => format!("{:>10} {}", "", object.key),
+        ListEntry::Object(object) => object.key.clone(),
+    }
+}
+
+fn format_long(object: &ObjectInfo, human_readable: bool) -> String {
+    format!(
+        "{:20} {:>12} {:<12} {:<34} {:<16} {}",
+        object
+            .last_modified
+            .map(|d| d.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_else(|| "Unknown".to_string()),
+        display_size(object.size, human_readable),
+        object.storage_class.as_deref().unwrap_or("-"),
+        object.e_tag.as_deref().unwrap_or("-"),
+        object.owner.as_deref().unwrap_or("-"),
+        object.key
+    )
+}
+
+fn format_head(head: &HeadObjectInfo) -> String {
+    let mut lines = vec![
+        format!("{:15} {}", "Key:", head.key),
+        format!("{:15} {}", "Size:", head.size),
+        format!("{:15} {}", "Last-Modified:", format_date(head.last_modified)),
+        format!("{:15} {}", "ETag:", head.e_tag.as_deref().unwrap_or("-")),
+        format!("{:15} {}", "Content-Type:", head.content_type.as_deref().unwrap_or("-")),
+        format!("{:15} {}", "Storage-Class:", head.storage_class.as_deref().unwrap_or("STANDARD")),
+    ];
+    lines.extend(
+        head.metadata
+            .iter()
+            .map(|(k, v)| format!("x-amz-meta-{}: {}", k, v)),
+    );
+    lines.join("\n")
+}
+
+fn display_size(bytes: u64, human_readable: bool) -> String {
+    if human_readable {
+        format_size(bytes)
+    } else {
+        bytes.to_string()
+    }
+}
@@ -1,2 +1,4 @@
 pub mod commands;
+pub mod output;
 pub mod parser;
+pub mod url;
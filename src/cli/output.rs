@@ -0,0 +1,267 @@
+use std::io::{self, Stdout};
+use std::marker::PhantomData;
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::s3::client::{BucketInfo, DeletedObject, HeadObjectInfo, ListEntry, TransferSummary};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-friendly text
+    #[default]
+    Text,
+    /// Aligned columns
+    Table,
+    /// A single JSON document
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+/// A result type that can be rendered in every [`OutputFormat`].
+///
+/// JSON and NDJSON go through `Serialize`; table and CSV use the flat
+/// `headers`/`row` view so nested fields can be collapsed into one cell.
+pub trait Record: Serialize {
+    fn headers() -> &'static [&'static str];
+    fn row(&self) -> Vec<String>;
+}
+
+/// Writes records to stdout in the selected format.
+///
+/// Everything except `table` is streamed as records arrive, so listings of
+/// millions of keys print with bounded memory. Text output is rendered by the
+/// caller-supplied closure, keeping each command's familiar layout.
+///
+/// A JSON array is closed even when the printer is dropped without
+/// [`Printer::finish`], e.g. because the stream being printed failed, so
+/// stdout still parses.
+pub struct Printer<R, F> {
+    format: OutputFormat,
+    text: F,
+    count: usize,
+    finished: bool,
+    csv: Option<csv::Writer<Stdout>>,
+    table: Vec<Vec<String>>,
+    _record: PhantomData<fn(&R)>,
+}
+
+impl<R, F> Printer<R, F>
+where
+    R: Record,
+    F: Fn(&R) -> String,
+{
+    pub fn new(format: OutputFormat, text: F) -> Self {
+        Printer {
+            format,
+            text,
+            count: 0,
+            finished: false,
+            csv: None,
+            table: Vec::new(),
+            _record: PhantomData,
+        }
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn print(&mut self, record: &R) -> Result<()> {
+        match self.format {
+            OutputFormat::Text => println!("{}", (self.text)(record)),
+            OutputFormat::Ndjson => println!("{}", serde_json::to_string(record)?),
+            OutputFormat::Json => {
+                let sep = if self.count == 0 { "[" } else { "," };
+                print!("{}\n  {}", sep, serde_json::to_string(record)?);
+            }
+            OutputFormat::Csv => self.csv_writer()?.write_record(record.row())?,
+            OutputFormat::Table => self.table.push(record.row()),
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Prints a lone result; JSON output is the bare object rather than an array.
+    pub fn print_one(mut self, record: &R) -> Result<()> {
+        if self.format == OutputFormat::Json {
+            self.finished = true;
+            println!("{}", serde_json::to_string_pretty(record)?);
+            return Ok(());
+        }
+        self.print(record)?;
+        self.finish()
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        match self.format {
+            OutputFormat::Json => close_json_array(self.count),
+            OutputFormat::Csv => self.csv_writer()?.flush()?,
+            OutputFormat::Table => print_table(R::headers(), &self.table),
+            OutputFormat::Text | OutputFormat::Ndjson => {}
+        }
+        Ok(())
+    }
+
+    fn csv_writer(&mut self) -> Result<&mut csv::Writer<Stdout>> {
+        if self.csv.is_none() {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record(R::headers())?;
+            self.csv = Some(writer);
+        }
+        Ok(self.csv.as_mut().unwrap())
+    }
+}
+
+impl<R, F> Drop for Printer<R, F> {
+    fn drop(&mut self) {
+        if self.format == OutputFormat::Json && !self.finished {
+            close_json_array(self.count);
+        }
+    }
+}
+
+fn close_json_array(count: usize) {
+    if count == 0 {
+        println!("[]");
+    } else {
+        println!("\n]");
+    }
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<String>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.iter().map(|h| h.to_uppercase()).collect());
+    for row in rows {
+        line(row.clone());
+    }
+}
+
+pub fn format_date(date: Option<DateTime<Utc>>) -> String {
+    date.map(|d| d.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+impl Record for BucketInfo {
+    fn headers() -> &'static [&'static str] {
+        &["name", "creation_date"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.name.clone(), format_date(self.creation_date)]
+    }
+}
+
+impl Record for ListEntry {
+    fn headers() -> &'static [&'static str] {
+        &[
+            "type",
+            "key",
+            "size",
+            "last_modified",
+            "e_tag",
+            "storage_class",
+            "owner",
+        ]
+    }
+
+    fn row(&self) -> Vec<String> {
+        match self {
+            ListEntry::Prefix { prefix } => {
+                let mut row = vec!["prefix".to_string(), prefix.clone()];
+                row.resize(Self::headers().len(), String::new());
+                row
+            }
+            ListEntry::Object(object) => vec![
+                "object".to_string(),
+                object.key.clone(),
+                object.size.to_string(),
+                format_date(object.last_modified),
+                object.e_tag.clone().unwrap_or_default(),
+                object.storage_class.clone().unwrap_or_default(),
+                object.owner.clone().unwrap_or_default(),
+            ],
+        }
+    }
+}
+
+impl Record for HeadObjectInfo {
+    fn headers() -> &'static [&'static str] {
+        &[
+            "key",
+            "size",
+            "last_modified",
+            "e_tag",
+            "content_type",
+            "storage_class",
+            "metadata",
+        ]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.key.clone(),
+            self.size.to_string(),
+            format_date(self.last_modified),
+            self.e_tag.clone().unwrap_or_default(),
+            self.content_type.clone().unwrap_or_default(),
+            self.storage_class.clone().unwrap_or_default(),
+            self.metadata
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(";"),
+        ]
+    }
+}
+
+impl Record for TransferSummary {
+    fn headers() -> &'static [&'static str] {
+        &["direction", "bucket", "key", "path", "bytes", "error"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.direction.as_str().to_string(),
+            self.bucket.clone(),
+            self.key.clone(),
+            self.path.clone(),
+            self.bytes.to_string(),
+            self.error.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+impl Record for DeletedObject {
+    fn headers() -> &'static [&'static str] {
+        &["bucket", "key", "version_id", "error"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.bucket.clone(),
+            self.key.clone(),
+            self.version_id.clone().unwrap_or_default(),
+            self.error.clone().unwrap_or_default(),
+        ]
+    }
+}
@@ -1,69 +1,430 @@
 use super::commands::Command;
-use clap::{Parser, Subcommand};
+use super::output::OutputFormat;
+use super::url::{parse_local_path, Location, S3Url};
+use crate::s3::client::{CopyOptions, Direction, SyncOptions, TransferOptions, MAX_PUT_SIZE};
+use crate::utils::parse_size;
+use clap::builder::RangedU64ValueParser;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "rs3")]
 #[command(about = "AWS S3 CLI", long_about = None)]
 pub struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Options accepted by every command.
+#[derive(Args)]
+pub struct GlobalArgs {
+    /// Output format
+    #[arg(short, long, global = true, value_enum, default_value_t)]
+    pub output: OutputFormat,
+    /// Profile from [profiles] in config.toml, instead of its default_profile
+    #[arg(long, global = true, env = "RS3_PROFILE")]
+    pub profile: Option<String>,
+}
+
+/// Files at or above this size are uploaded and downloaded in parts unless
+/// `--multipart-threshold` says otherwise.
+const DEFAULT_MULTIPART_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// Multipart tuning shared by transfer commands.
+#[derive(Args, Clone, Copy)]
+struct TransferArgs {
+    /// Size of each part in a multipart transfer, e.g. 8MiB
+    #[arg(long, value_parser = parse_size, default_value = "8MiB")]
+    part_size: u64,
+    /// Files at or above this size are transferred in parts [default: 8MiB, or
+    /// 5GiB for copies between buckets]
+    #[arg(long, value_parser = parse_size)]
+    multipart_threshold: Option<u64>,
+    /// Number of parts transferred in parallel
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 10)]
+    concurrency: usize,
+    /// Abort failed multipart transfers instead of keeping a checkpoint to resume from
+    #[arg(long)]
+    no_resume: bool,
+}
+
+impl From<TransferArgs> for TransferOptions {
+    fn from(args: TransferArgs) -> Self {
+        TransferOptions {
+            multipart_threshold: args
+                .multipart_threshold
+                .unwrap_or(DEFAULT_MULTIPART_THRESHOLD),
+            part_size: args.part_size,
+            concurrency: args.concurrency,
+            resumable: !args.no_resume,
+        }
+    }
+}
+
+impl TransferArgs {
+    /// Options for copies between buckets. They stay single CopyObject
+    /// requests up to the 5 GiB limit unless `--multipart-threshold` is given,
+    /// since a multipart copy gets a multipart ETag.
+    fn for_copies(self) -> TransferOptions {
+        TransferOptions {
+            multipart_threshold: self.multipart_threshold.unwrap_or(MAX_PUT_SIZE),
+            ..self.into()
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     ListBuckets,
     CreateBucket {
-        name: String,
+        /// Bucket URL, e.g. s3://my-bucket
+        #[arg(value_parser = S3Url::parse_new_bucket)]
+        url: S3Url,
     },
     DeleteBucket {
-        name: String,
+        /// Bucket URL, e.g. s3://my-bucket
+        #[arg(value_parser = S3Url::parse_bucket)]
+        url: S3Url,
+        /// Delete all object versions, delete markers and multipart uploads first
+        #[arg(short, long)]
+        force: bool,
+        /// Skip the confirmation prompt for --force
+        #[arg(short, long, requires = "force")]
+        yes: bool,
+        /// Number of DeleteObjects batches sent in parallel with --force
+        #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 10)]
+        concurrency: usize,
     },
     ListObjects {
-        bucket: String,
+        /// Bucket or prefix URL, e.g. s3://my-bucket/logs/
+        url: S3Url,
+        /// Delimiter used to group keys into common prefixes
+        #[arg(short, long, default_value = "/")]
+        delimiter: String,
+        /// List every key under the prefix instead of one level
         #[arg(short, long)]
-        prefix: Option<String>,
+        recursive: bool,
+        /// Show size, last-modified, storage class, ETag and owner
+        #[arg(short, long)]
+        long: bool,
+        /// Print sizes in KB, MB, GB, ... instead of bytes
+        #[arg(long)]
+        human_readable: bool,
+    },
+    /// Copy files and objects; the direction follows from which side is an s3:// URL
+    Cp {
+        /// Local path or s3:// URL to copy from
+        src: Location,
+        /// Local path or s3:// URL to copy to; a trailing '/' keeps the source file name
+        dst: Location,
+        /// Copy every file or object under SRC
+        #[arg(short, long)]
+        recursive: bool,
+        /// Drop the source's tags on bucket-to-bucket copies, saving a
+        /// GetObjectTagging request per multipart copy
+        #[arg(long)]
+        no_tags: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Move objects server-side, deleting each source once its copy is confirmed
+    Mv {
+        /// Object URL, or the key prefix URL with --recursive
+        src: S3Url,
+        /// Destination URL; a trailing '/' keeps the source file name
+        dst: S3Url,
+        /// Move every object under the SRC prefix
+        #[arg(short, long)]
+        recursive: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Make DST match SRC, transferring only new and changed files
+    Sync {
+        /// Local directory or s3:// prefix URL to sync from. Between buckets,
+        /// s3://<alias>/<bucket>/<prefix> selects an endpoint from [aliases] in config
+        src: Location,
+        /// Local directory or s3:// prefix URL to sync to
+        dst: Location,
+        /// Delete destination files that do not exist at the source
+        #[arg(long)]
+        delete: bool,
+        /// Print what would be transferred and deleted without doing it
+        #[arg(long)]
+        dry_run: bool,
+        /// Compare MD5 checksums with ETags instead of modification times
+        #[arg(long)]
+        checksum: bool,
+        /// Remember synced files in the local directory's .rs3-sync-state, so
+        /// later uploads only list the prefixes of files that changed locally
+        #[arg(long)]
+        state: bool,
+        /// Discard the sync state and rebuild it from a full comparison
+        #[arg(long)]
+        rebuild_state: bool,
+        /// Keep running and upload files as they change, using inotify (Linux only)
+        #[arg(long, conflicts_with = "dry_run")]
+        watch: bool,
+        /// With --watch, upload a file once it has not changed for this long
+        #[arg(long, value_name = "SECONDS", default_value_t = 2, requires = "watch")]
+        settle: u64,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     UploadObject {
-        bucket: String,
-        key: String,
+        /// Local file, or directory with --recursive
+        #[arg(value_parser = parse_local_path)]
         file_path: String,
+        /// Destination object URL; a URL ending in '/' appends the file name.
+        /// With --recursive, the key prefix to upload under
+        url: S3Url,
+        /// Upload every file under the FILE_PATH directory
+        #[arg(short, long)]
+        recursive: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     DownloadObject {
-        bucket: String,
-        key: String,
+        /// Object URL, or the key prefix URL with --recursive
+        url: S3Url,
+        /// Local file; an existing directory receives the object's file name.
+        /// With --recursive, the directory to download into
+        #[arg(value_parser = parse_local_path)]
         file_path: String,
+        /// Download every object under the prefix into the FILE_PATH directory
+        #[arg(short, long)]
+        recursive: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     DeleteObject {
-        bucket: String,
-        key: String,
+        /// Object URL, or the key prefix URL with --recursive
+        url: S3Url,
+        /// Delete every object under the prefix
+        #[arg(short, long)]
+        recursive: bool,
+        /// Number of DeleteObjects batches sent in parallel
+        #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 10)]
+        concurrency: usize,
+    },
+    HeadObject {
+        /// Object URL, e.g. s3://my-bucket/key
+        #[arg(value_parser = S3Url::parse_object)]
+        url: S3Url,
     },
 }
 
-pub fn parse_cli() -> Command {
+/// Exits with a usage error unless `url` names an object, for commands where
+/// only `--recursive` allows a bare bucket or prefix.
+fn require_key(url: &S3Url, recursive: bool) {
+    if !recursive && url.is_dir() {
+        Cli::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("'{}' is not an object URL; pass --recursive to operate on a prefix", url),
+            )
+            .exit();
+    }
+}
+
+pub fn parse_cli() -> (GlobalArgs, Command) {
     let cli = Cli::parse();
-    match cli.command {
+    let command = match cli.command {
         Commands::ListBuckets => Command::ListBuckets,
-        Commands::CreateBucket { name } => Command::CreateBucket { name },
-        Commands::DeleteBucket { name } => Command::DeleteBucket { name },
-        Commands::ListObjects { bucket, prefix } => Command::ListObjects { bucket, prefix },
+        Commands::CreateBucket { url } => Command::CreateBucket { url },
+        Commands::DeleteBucket {
+            url,
+            force,
+            yes,
+            concurrency,
+        } => Command::DeleteBucket {
+            url,
+            force,
+            yes,
+            concurrency,
+        },
+        Commands::ListObjects {
+            url,
+            delimiter,
+            recursive,
+            long,
+            human_readable,
+        } => Command::ListObjects {
+            url,
+            delimiter: (!recursive).then_some(delimiter),
+            long,
+            human_readable,
+        },
+        Commands::Cp {
+            src,
+            dst,
+            recursive,
+            no_tags,
+            transfer,
+        } => match (src, dst) {
+            (Location::Local(file_path), Location::S3(url)) => Command::UploadObject {
+                file_path,
+                url,
+                recursive,
+                options: transfer.into(),
+            },
+            (Location::S3(url), Location::Local(file_path)) => {
+                require_key(&url, recursive);
+                Command::DownloadObject {
+                    url,
+                    file_path,
+                    recursive,
+                    options: transfer.into(),
+                }
+            }
+            (Location::S3(src), Location::S3(dst)) => {
+                require_key(&src, recursive);
+                Command::CopyObject {
+                    src,
+                    dst,
+                    recursive,
+                    options: CopyOptions {
+                        transfer: transfer.for_copies(),
+                        tags: !no_tags,
+                    },
+                }
+            }
+            (Location::Local(_), Location::Local(_)) => Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "at least one of SRC and DST must be an s3:// URL",
+                )
+                .exit(),
+        },
+        Commands::Mv {
+            src,
+            dst,
+            recursive,
+            transfer,
+        } => {
+            require_key(&src, recursive);
+            Command::MoveObject {
+                src,
+                dst,
+                recursive,
+                // A rename should not lose the object's metadata or tags.
+                options: CopyOptions {
+                    transfer: transfer.for_copies(),
+                    tags: true,
+                },
+            }
+        }
+        Commands::Sync {
+            src,
+            dst,
+            delete,
+            dry_run,
+            checksum,
+            state,
+            rebuild_state,
+            watch,
+            settle,
+            transfer,
+        } => {
+            let copies = transfer.for_copies();
+            let options = SyncOptions {
+                transfer: transfer.into(),
+                delete,
+                checksum,
+                state: state || rebuild_state,
+                rebuild_state,
+            };
+            match (src, dst) {
+                (Location::Local(dir), Location::S3(url)) if watch => Command::WatchSync {
+                    dir,
+                    url,
+                    options,
+                    settle: Duration::from_secs(settle),
+                },
+                (_, _) if watch => Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--watch syncs a local directory to an s3:// URL",
+                    )
+                    .exit(),
+                (Location::Local(dir), Location::S3(url)) => Command::Sync {
+                    direction: Direction::Upload,
+                    dir,
+                    url,
+                    options,
+                    dry_run,
+                },
+                (Location::S3(url), Location::Local(dir)) => Command::Sync {
+                    direction: Direction::Download,
+                    dir,
+                    url,
+                    options,
+                    dry_run,
+                },
+                (Location::S3(_), Location::S3(_)) if options.state => Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--state needs a local directory on one side of the sync",
+                    )
+                    .exit(),
+                (Location::S3(src), Location::S3(dst)) => Command::SyncBuckets {
+                    src,
+                    dst,
+                    options: SyncOptions {
+                        transfer: copies,
+                        ..options
+                    },
+                    dry_run,
+                },
+                (Location::Local(_), Location::Local(_)) => Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "at least one of SRC and DST must be an s3:// URL",
+                    )
+                    .exit(),
+            }
+        }
         Commands::UploadObject {
-            bucket,
-            key,
             file_path,
+            url,
+            recursive,
+            transfer,
         } => Command::UploadObject {
-            bucket,
-            key,
             file_path,
+            url,
+            recursive,
+            options: transfer.into(),
         },
         Commands::DownloadObject {
-            bucket,
-            key,
+            url,
             file_path,
-        } => Command::DownloadObject {
-            bucket,
-            key,
-            file_path,
-        },
-        Commands::DeleteObject { bucket, key } => Command::DeleteObject { bucket, key },
-    }
+            recursive,
+            transfer,
+        } => {
+            require_key(&url, recursive);
+            Command::DownloadObject {
+                url,
+                file_path,
+                recursive,
+                options: transfer.into(),
+            }
+        }
+        Commands::DeleteObject {
+            url,
+            recursive,
+            concurrency,
+        } => {
+            require_key(&url, recursive);
+            Command::DeleteObject {
+                url,
+                recursive,
+                concurrency,
+            }
+        }
+        Commands::HeadObject { url } => Command::HeadObject { url },
+    };
+    (cli.global, command)
 }
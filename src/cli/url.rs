@@ -0,0 +1,231 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+const SCHEME: &str = "s3://";
+/// S3 rejects keys longer than this many UTF-8 bytes.
+const MAX_KEY_LEN: usize = 1024;
+
+/// An `s3://bucket/key` address. The key may be empty, in which case the URL
+/// names the whole bucket (or the root prefix when listing).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct S3Url {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Url {
+    /// Parses a URL that must name a bucket only, e.g. `s3://my-bucket`.
+    pub fn parse_bucket(s: &str) -> Result<Self, String> {
+        let url: S3Url = s.parse()?;
+        if !url.key.is_empty() {
+            return Err(format!("expected a bucket URL like s3://{}, got '{}'", url.bucket, s));
+        }
+        Ok(url)
+    }
+
+    /// Parses the URL of a bucket to create, which must also follow the S3
+    /// general purpose bucket naming rules. Existing buckets are not held to
+    /// them: legacy and Ceph RGW buckets may use uppercase letters or `_`.
+    pub fn parse_new_bucket(s: &str) -> Result<Self, String> {
+        let url = Self::parse_bucket(s)?;
+        validate_bucket_name(&url.bucket)?;
+        Ok(url)
+    }
+
+    /// Parses a URL that must name a single object, e.g. `s3://my-bucket/key`.
+    pub fn parse_object(s: &str) -> Result<Self, String> {
+        let url: S3Url = s.parse()?;
+        if url.key.is_empty() {
+            return Err(format!("expected an object URL like s3://{}/key, got '{}'", url.bucket, s));
+        }
+        Ok(url)
+    }
+
+    /// Whether the key is empty or ends in `/`, i.e. addresses a "directory".
+    pub fn is_dir(&self) -> bool {
+        self.key.is_empty() || self.key.ends_with('/')
+    }
+
+    /// The last segment of the key, e.g. `c.txt` for `s3://b/a/c.txt`.
+    pub fn file_name(&self) -> Option<&str> {
+        self.key.rsplit('/').next().filter(|name| !name.is_empty())
+    }
+}
+
+impl FromStr for S3Url {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| format!("'{}' is not an s3:// URL", s))?;
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(format!("'{}' has no bucket name", s));
+        }
+        validate_key(key)?;
+        Ok(S3Url {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for S3Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}/{}", SCHEME, self.bucket, self.key)
+    }
+}
+
+/// Either side of a `cp`: an `s3://` URL or a plain local path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    Local(String),
+    S3(S3Url),
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(SCHEME) {
+            s.parse().map(Location::S3)
+        } else {
+            parse_local_path(s).map(Location::Local)
+        }
+    }
+}
+
+/// Parses a local filesystem path, rejecting `s3://` URLs so a swapped
+/// argument order fails up front instead of creating a file named `s3:`.
+pub fn parse_local_path(s: &str) -> Result<String, String> {
+    if s.starts_with(SCHEME) {
+        return Err(format!("expected a local path, got the S3 URL '{}'", s));
+    }
+    if s.is_empty() {
+        return Err("local path must not be empty".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Applies the S3 general purpose bucket naming rules.
+fn validate_bucket_name(name: &str) -> Result<(), String> {
+    let invalid = |reason: &str| Err(format!("invalid bucket name '{}': {}", name, reason));
+    if !(3..=63).contains(&name.len()) {
+        return invalid("must be between 3 and 63 characters long");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+    {
+        return invalid("only lowercase letters, digits, '.' and '-' are allowed");
+    }
+    let alphanumeric = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    if !alphanumeric(name.chars().next()) || !alphanumeric(name.chars().last()) {
+        return invalid("must begin and end with a letter or digit");
+    }
+    if name.contains("..") {
+        return invalid("must not contain two adjacent periods");
+    }
+    if name.parse::<Ipv4Addr>().is_ok() {
+        return invalid("must not be formatted as an IP address");
+    }
+    if name.starts_with("xn--") || name.starts_with("sthree-") {
+        return invalid("uses a reserved prefix");
+    }
+    if name.ends_with("-s3alias") || name.ends_with("--ol-s3") {
+        return invalid("uses a reserved suffix");
+    }
+    Ok(())
+}
+
+fn validate_key(key: &str) -> Result<(), String> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(format!(
+            "object key is {} bytes long, the limit is {}",
+            key.len(),
+            MAX_KEY_LEN
+        ));
+    }
+    if key.contains('\0') {
+        return Err("object key must not contain NUL characters".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(bucket: &str, key: &str) -> S3Url {
+        S3Url {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_bucket_and_key() {
+        assert_eq!("s3://my-bucket".parse(), Ok(url("my-bucket", "")));
+        assert_eq!("s3://my-bucket/".parse(), Ok(url("my-bucket", "")));
+        assert_eq!(
+            "s3://my-bucket/a/b.txt".parse(),
+            Ok(url("my-bucket", "a/b.txt"))
+        );
+        assert_eq!(
+            "s3://my-bucket/logs/".parse(),
+            Ok(url("my-bucket", "logs/"))
+        );
+    }
+
+    #[test]
+    fn accepts_legacy_bucket_names_and_aliases() {
+        assert_eq!(
+            "s3://Legacy_Bucket/k".parse(),
+            Ok(url("Legacy_Bucket", "k"))
+        );
+        assert_eq!("s3://rgw_prod/b/k".parse(), Ok(url("rgw_prod", "b/k")));
+    }
+
+    #[test]
+    fn rejects_malformed_urls() {
+        for s in ["my-bucket/key", "s3:/my-bucket", "s3://", "s3:///key"] {
+            assert!(s.parse::<S3Url>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert!("s3://b/a\0b".parse::<S3Url>().is_err());
+        let long = format!("s3://b/{}", "k".repeat(MAX_KEY_LEN + 1));
+        assert!(long.parse::<S3Url>().is_err());
+        let longest = format!("s3://b/{}", "k".repeat(MAX_KEY_LEN));
+        assert!(longest.parse::<S3Url>().is_ok());
+    }
+
+    #[test]
+    fn new_buckets_follow_the_naming_rules() {
+        assert!(S3Url::parse_new_bucket("s3://my-bucket.2024").is_ok());
+        for s in [
+            "s3://ab",
+            "s3://Upper",
+            "s3://under_score",
+            "s3://-dash",
+            "s3://two..dots",
+            "s3://192.168.0.1",
+            "s3://xn--bucket",
+            "s3://bucket-s3alias",
+        ] {
+            assert!(S3Url::parse_new_bucket(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn bucket_and_object_urls() {
+        assert!(S3Url::parse_bucket("s3://b/key").is_err());
+        assert!(S3Url::parse_object("s3://b").is_err());
+        assert!(S3Url::parse_object("s3://b/").is_err());
+        assert_eq!(S3Url::parse_object("s3://b/k"), Ok(url("b", "k")));
+    }
+}
@@ -1,23 +1,385 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
 use config::{Config, Environment, File};
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+/// How many roles may be chained through `source_profile` before rs3 gives
+/// up on what is most likely a cycle.
+const MAX_ROLE_CHAIN: usize = 8;
+
+/// The connection settings in effect: the top-level settings of `config.toml`
+/// and `RS3_*` environment variables, overridden by the selected profile.
+///
+/// Settings left out are looked up the way the AWS CLI does: credentials
+/// through the standard chain (`AWS_*` variables, `~/.aws/credentials` and
+/// `~/.aws/config`, web identity, ECS and EC2 instance metadata), the region
+/// from `AWS_REGION` or the shared config, and the endpoint is AWS's own.
+#[derive(Clone, Debug)]
 pub struct AppConfig {
-    pub access_key: String,
-    pub secret_key: String,
-    pub region: String,
-    pub endpoint_url: String,
+    /// Set together with `secret_key`, or not at all.
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    /// Makes the keys temporary credentials.
+    pub session_token: Option<String>,
+    /// A command printing credentials as JSON, run again once they expire.
+    pub credential_process: Option<String>,
+    pub region: Option<String>,
+    pub endpoint_url: Option<String>,
     pub path_style: bool,
+    /// The profile in `~/.aws/config` to take credentials and the region from
+    /// instead of `AWS_PROFILE` or `default`.
+    pub aws_profile: Option<String>,
+    /// Set when the credentials come from assuming a role.
+    pub assume_role: Option<AssumeRole>,
+    /// The settings these were resolved from, for aliases to override.
+    settings: Settings,
+    file: Arc<ConfigFile>,
+}
+
+/// A role to assume through STS `AssumeRole`.
+#[derive(Clone, Debug)]
+pub struct AssumeRole {
+    pub role_arn: String,
+    pub external_id: Option<String>,
+    /// How long the role's credentials last; STS defaults to an hour.
+    pub duration: Option<Duration>,
+    /// Where to send `AssumeRole`, e.g. a local stand-in for testing;
+    /// AWS's regional STS endpoint by default.
+    pub sts_endpoint_url: Option<String>,
+    /// The settings whose credentials the role is assumed with.
+    pub source: Box<AppConfig>,
+}
+
+/// The top-level settings, or a `[profiles.<name>]` or `[aliases.<name>]`
+/// table. Fields a table leaves out fall back to the settings it overrides,
+/// so it may only change the endpoint or the credentials; the role fields
+/// are the exception and only apply to the table that sets them.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Settings {
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    pub session_token: Option<String>,
+    /// Run through `sh -c`; prints credentials in the JSON format of the AWS
+    /// CLI's `credential_process`, with an optional `Expiration`.
+    pub credential_process: Option<String>,
+    pub region: Option<String>,
+    pub endpoint_url: Option<String>,
+    pub path_style: Option<bool>,
+    pub aws_profile: Option<String>,
+    pub role_arn: Option<String>,
+    /// The profile whose credentials assume `role_arn`; without one, the
+    /// credentials these settings would use on their own do.
+    pub source_profile: Option<String>,
+    pub external_id: Option<String>,
+    /// Lifetime of the role's credentials in seconds.
+    pub duration: Option<u64>,
+    pub sts_endpoint_url: Option<String>,
+}
+
+/// The tables of `config.toml` besides the top-level settings.
+#[derive(Debug, Deserialize)]
+struct Tables {
+    /// The profile used when neither `--profile` nor `RS3_PROFILE` names one.
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Settings>,
+    #[serde(default)]
+    aliases: HashMap<String, Settings>,
+}
+
+/// Everything loaded from `config.toml` and the environment.
+#[derive(Debug)]
+struct ConfigFile {
+    top: Settings,
+    tables: Tables,
 }
 
 impl AppConfig {
-    pub fn load() -> Result<Self> {
+    /// Loads the settings of `profile`, or of the configured
+    /// `default_profile` when it is `None`. Without either, only the
+    /// top-level settings are used.
+    pub fn load(profile: Option<&str>) -> Result<Self> {
         let config = Config::builder()
             .add_source(File::with_name("config").required(false))
             .add_source(Environment::with_prefix("RS3"))
             .build()?;
 
-        Ok(config.try_deserialize()?)
+        let file = ConfigFile {
+            top: config.clone().try_deserialize()?,
+            tables: config.try_deserialize()?,
+        };
+        let settings = match profile.or(file.tables.default_profile.as_deref()) {
+            Some(name) => file.profile(name)?,
+            None => file.top.clone(),
+        };
+        Self::resolve(settings, Arc::new(file), 0)
+    }
+
+    /// The connection settings for `alias`, or `None` if no such alias is
+    /// configured.
+    pub fn alias(&self, alias: &str) -> Result<Option<AppConfig>> {
+        let Some(overrides) = self.file.tables.aliases.get(alias) else {
+            return Ok(None);
+        };
+        Self::resolve(overrides.or(&self.settings), Arc::clone(&self.file), 0).map(Some)
+    }
+
+    /// Whether `name` is one of the configured aliases.
+    pub fn has_alias(&self, name: &str) -> bool {
+        self.file.tables.aliases.contains_key(name)
+    }
+
+    fn resolve(settings: Settings, file: Arc<ConfigFile>, depth: usize) -> Result<Self> {
+        if settings.access_key.is_some() != settings.secret_key.is_some() {
+            bail!("Set both access_key and secret_key, or neither to use the AWS credential chain");
+        }
+        if settings.access_key.is_some() && settings.credential_process.is_some() {
+            bail!("Set either access_key and secret_key or credential_process, not both");
+        }
+        let assume_role = match &settings.role_arn {
+            Some(role_arn) => {
+                if depth >= MAX_ROLE_CHAIN {
+                    bail!(
+                        "More than {} roles are chained through source_profile; is there a cycle?",
+                        MAX_ROLE_CHAIN
+                    );
+                }
+                let source = match &settings.source_profile {
+                    Some(name) => file.profile(name)?,
+                    None => Settings {
+                        role_arn: None,
+                        source_profile: None,
+                        external_id: None,
+                        duration: None,
+                        sts_endpoint_url: None,
+                        ..settings.clone()
+                    },
+                };
+                Some(AssumeRole {
+                    role_arn: role_arn.clone(),
+                    external_id: settings.external_id.clone(),
+                    duration: settings.duration.map(Duration::from_secs),
+                    sts_endpoint_url: settings.sts_endpoint_url.clone(),
+                    source: Box::new(Self::resolve(source, Arc::clone(&file), depth + 1)?),
+                })
+            }
+            None => None,
+        };
+        Ok(AppConfig {
+            access_key: settings.access_key.clone(),
+            secret_key: settings.secret_key.clone(),
+            session_token: settings.session_token.clone(),
+            credential_process: settings.credential_process.clone(),
+            region: settings.region.clone(),
+            endpoint_url: settings.endpoint_url.clone(),
+            path_style: settings.path_style.unwrap_or(false),
+            aws_profile: settings.aws_profile.clone(),
+            assume_role,
+            settings,
+            file,
+        })
+    }
+}
+
+impl ConfigFile {
+    /// The settings of the profile `name` over the top-level ones.
+    fn profile(&self, name: &str) -> Result<Settings> {
+        let Some(profile) = self.tables.profiles.get(name) else {
+            let mut known: Vec<_> = self.tables.profiles.keys().map(String::as_str).collect();
+            known.sort_unstable();
+            bail!(
+                "Profile '{}' is not defined; the config has [profiles] for: {}",
+                name,
+                if known.is_empty() {
+                    "none".to_string()
+                } else {
+                    known.join(", ")
+                }
+            );
+        };
+        Ok(profile.or(&self.top))
+    }
+}
+
+impl Settings {
+    /// These settings, with the fields they leave out taken from `base`.
+    ///
+    /// Settings that bring their own keys, `credential_process` or
+    /// `aws_profile` inherit none of the base's credentials, session token
+    /// included, so a profile can swap one kind for another. The role is
+    /// never inherited: a top-level `role_arn` would otherwise also be
+    /// assumed by its own `source_profile`, over and over.
+    fn or(&self, base: &Settings) -> Settings {
+        let or = |value: &Option<String>, base: &Option<String>| value.clone().or(base.clone());
+        let base = if self.access_key.is_some()
+            || self.credential_process.is_some()
+            || self.aws_profile.is_some()
+        {
+            &Settings {
+                access_key: None,
+                secret_key: None,
+                session_token: None,
+                credential_process: None,
+                ..base.clone()
+            }
+        } else {
+            base
+        };
+        Settings {
+            access_key: or(&self.access_key, &base.access_key),
+            secret_key: or(&self.secret_key, &base.secret_key),
+            session_token: or(&self.session_token, &base.session_token),
+            credential_process: or(&self.credential_process, &base.credential_process),
+            region: or(&self.region, &base.region),
+            endpoint_url: or(&self.endpoint_url, &base.endpoint_url),
+            path_style: self.path_style.or(base.path_style),
+            aws_profile: or(&self.aws_profile, &base.aws_profile),
+            role_arn: self.role_arn.clone(),
+            source_profile: self.source_profile.clone(),
+            external_id: self.external_id.clone(),
+            duration: self.duration,
+            sts_endpoint_url: or(&self.sts_endpoint_url, &base.sts_endpoint_url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    fn file(toml: &str) -> Arc<ConfigFile> {
+        let config = Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap();
+        Arc::new(ConfigFile {
+            top: config.clone().try_deserialize().unwrap(),
+            tables: config.try_deserialize().unwrap(),
+        })
+    }
+
+    fn profile(file: &Arc<ConfigFile>, name: &str) -> Result<AppConfig> {
+        AppConfig::resolve(file.profile(name)?, Arc::clone(file), 0)
+    }
+
+    const TOP_KEYS: &str = r#"
+        access_key = "top_access"
+        secret_key = "top_secret"
+        session_token = "top_token"
+        region = "us-east-1"
+        endpoint_url = "https://top.example.com"
+        path_style = true
+    "#;
+
+    #[test]
+    fn profiles_fall_back_to_the_top_level() {
+        let file = file(&format!(
+            "{}\n[profiles.lab]\nendpoint_url = \"https://lab.example.com\"\n",
+            TOP_KEYS
+        ));
+        let lab = profile(&file, "lab").unwrap();
+        assert_eq!(lab.endpoint_url.as_deref(), Some("https://lab.example.com"));
+        assert_eq!(lab.region.as_deref(), Some("us-east-1"));
+        assert!(lab.path_style);
+        assert_eq!(lab.access_key.as_deref(), Some("top_access"));
+        assert_eq!(lab.session_token.as_deref(), Some("top_token"));
+        assert!(profile(&file, "missing").is_err());
+    }
+
+    #[test]
+    fn credentials_replace_the_top_level_ones() {
+        let file = file(&format!(
+            r#"{}
+            [profiles.keys]
+            access_key = "own_access"
+            secret_key = "own_secret"
+            [profiles.chain]
+            aws_profile = "ci"
+            [profiles.process]
+            credential_process = "print-credentials"
+            "#,
+            TOP_KEYS
+        ));
+        let keys = profile(&file, "keys").unwrap();
+        assert_eq!(keys.access_key.as_deref(), Some("own_access"));
+        assert_eq!(keys.session_token, None);
+        let chain = profile(&file, "chain").unwrap();
+        assert_eq!(chain.aws_profile.as_deref(), Some("ci"));
+        assert_eq!(chain.access_key, None);
+        assert_eq!(chain.session_token, None);
+        let process = profile(&file, "process").unwrap();
+        assert_eq!(
+            process.credential_process.as_deref(),
+            Some("print-credentials")
+        );
+        assert_eq!(process.access_key, None);
+        assert_eq!(process.session_token, None);
+        assert_eq!(process.region.as_deref(), Some("us-east-1"));
+    }
+
+    #[test]
+    fn roles_are_assumed_with_the_source_profile() {
+        let file = file(
+            r#"
+            [profiles.base]
+            access_key = "base_access"
+            secret_key = "base_secret"
+            [profiles.deploy]
+            role_arn = "arn:aws:iam::123456789012:role/deploy"
+            source_profile = "base"
+            duration = 900
+            [profiles.admin]
+            role_arn = "arn:aws:iam::123456789012:role/admin"
+            source_profile = "deploy"
+            "#,
+        );
+        let admin = profile(&file, "admin").unwrap();
+        let role = admin.assume_role.unwrap();
+        assert_eq!(role.role_arn, "arn:aws:iam::123456789012:role/admin");
+        assert_eq!(role.duration, None);
+        let deploy = role.source.assume_role.unwrap();
+        assert_eq!(deploy.role_arn, "arn:aws:iam::123456789012:role/deploy");
+        assert_eq!(deploy.duration, Some(Duration::from_secs(900)));
+        assert_eq!(deploy.source.access_key.as_deref(), Some("base_access"));
+        assert!(deploy.source.assume_role.is_none());
+    }
+
+    #[test]
+    fn top_level_roles_are_not_inherited() {
+        let file = file(
+            r#"
+            role_arn = "arn:aws:iam::123456789012:role/deploy"
+            source_profile = "base"
+            external_id = "shared-secret"
+            [profiles.base]
+            access_key = "base_access"
+            secret_key = "base_secret"
+            "#,
+        );
+        let top = AppConfig::resolve(file.top.clone(), Arc::clone(&file), 0).unwrap();
+        let role = top.assume_role.unwrap();
+        assert_eq!(role.external_id.as_deref(), Some("shared-secret"));
+        assert_eq!(role.source.access_key.as_deref(), Some("base_access"));
+        assert!(role.source.assume_role.is_none());
+    }
+
+    #[test]
+    fn role_cycles_are_rejected() {
+        let file = file(
+            r#"
+            [profiles.a]
+            role_arn = "arn:aws:iam::123456789012:role/a"
+            source_profile = "b"
+            [profiles.b]
+            role_arn = "arn:aws:iam::123456789012:role/b"
+            source_profile = "a"
+            "#,
+        );
+        let error = profile(&file, "a").unwrap_err().to_string();
+        assert!(error.contains("cycle"), "{}", error);
     }
 }
@@ -8,7 +8,6 @@ use crate::s3::client::S3Client;
 
 mod cli;
 mod config;
-mod error;
 mod s3;
 mod utils;
 
@@ -16,12 +15,12 @@ mod utils;
 async fn main() -> Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let config = AppConfig::load()?;
-    let s3_client = S3Client::new(&config)?;
-    let command = parse_cli();
+    let (args, command) = parse_cli();
+    let config = AppConfig::load(args.profile.as_deref())?;
+    let s3_client = S3Client::new(&config).await?;
 
-    if let Err(e) = command.execute(&s3_client).await {
-        error!("Error: {}", e);
+    if let Err(e) = command.execute(&config, &s3_client, args.output).await {
+        error!("Error: {:#}", e);
         std::process::exit(1);
     }
 
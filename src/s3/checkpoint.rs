@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Identifies a local file's contents without reading them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFingerprint {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub modified: u64,
+}
+
+impl FileFingerprint {
+    pub fn of(path: &Path) -> Result<Self> {
+        Self::from_metadata(&fs::metadata(path)?)
+    }
+
+    pub fn from_metadata(metadata: &Metadata) -> Result<Self> {
+        Ok(FileFingerprint {
+            size: metadata.len(),
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+        })
+    }
+}
+
+/// A [`FileFingerprint`] plus the file's inode, so a file replaced by another
+/// with the same size and modification time still counts as changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileIdentity {
+    #[serde(flatten)]
+    pub fingerprint: FileFingerprint,
+    pub device: u64,
+    pub inode: u64,
+}
+
+impl FileIdentity {
+    pub fn from_metadata(metadata: &Metadata) -> Result<Self> {
+        let (device, inode) = inode(metadata);
+        Ok(FileIdentity {
+            fingerprint: FileFingerprint::from_metadata(metadata)?,
+            device,
+            inode,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CheckpointPart {
+    pub part_number: i32,
+    pub e_tag: String,
+}
+
+/// Progress of a multipart upload, persisted next to the source file so an
+/// interrupted upload can be resumed by re-running the same command.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadCheckpoint {
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+    pub part_size: u64,
+    pub source: FileFingerprint,
+    pub parts: Vec<CheckpointPart>,
+}
+
+impl UploadCheckpoint {
+    pub fn path_for(file: &Path) -> PathBuf {
+        with_suffix(file, ".rs3-upload")
+    }
+
+    pub fn load(path: &Path) -> Option<Self> {
+        load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save(self, path)
+    }
+
+    pub fn remove(path: &Path) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Progress of a download into a `.part` file, persisted next to it so an
+/// interrupted download can continue from the bytes already on disk.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadCheckpoint {
+    pub bucket: String,
+    pub key: String,
+    /// ETag observed when the download started; every request is pinned to it.
+    pub e_tag: String,
+    pub size: u64,
+    /// Range size for ranged downloads, `None` for a single streamed GET.
+    pub part_size: Option<u64>,
+    /// Ranges already written, by part number.
+    pub parts: Vec<i32>,
+}
+
+impl DownloadCheckpoint {
+    pub fn path_for(file: &Path) -> PathBuf {
+        with_suffix(file, ".rs3-download")
+    }
+
+    pub fn load(path: &Path) -> Option<Self> {
+        load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save(self, path)
+    }
+
+    pub fn remove(path: &Path) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// A file that was in sync with its object when the state was saved.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncedFile {
+    #[serde(flatten)]
+    pub local: FileIdentity,
+    pub e_tag: Option<String>,
+}
+
+/// What the last `sync --state` of a directory left in sync, persisted in the
+/// directory so the next run can skip files that have not changed since.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncState {
+    pub bucket: String,
+    pub prefix: String,
+    /// Keyed by the path relative to both the directory and `prefix`.
+    pub files: BTreeMap<String, SyncedFile>,
+}
+
+impl SyncState {
+    pub fn path_for(dir: &Path) -> PathBuf {
+        dir.join(".rs3-sync-state")
+    }
+
+    pub fn load(path: &Path) -> Option<Self> {
+        load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save(self, path)
+    }
+}
+
+/// Whether `path` is one of the checkpoint or sync state files written by rs3
+/// itself.
+pub fn is_checkpoint(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    let name = name.strip_suffix(".tmp").unwrap_or(name);
+    name.ends_with(".rs3-upload") || name.ends_with(".rs3-download") || name == ".rs3-sync-state"
+}
+
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Reads a checkpoint, treating a missing or unreadable file as absent.
+fn load<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let data = fs::read(path).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Writes a checkpoint atomically so a crash never leaves it truncated.
+fn save<T: Serialize>(value: &T, path: &Path) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -1,16 +1,32 @@
+use std::collections::BTreeMap;
+
 use crate::config::app_config::AppConfig;
-use anyhow::Result;
-use aws_config::SdkConfig;
-use aws_sdk_s3::{Client as AwsS3Client, Client, Config};
+use anyhow::{bail, Result};
+use aws_sdk_s3::{Client as AwsS3Client, Client};
 use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::config::{BehaviorVersion, Builder, Credentials, Region, SharedCredentialsProvider};
-use aws_sdk_s3::types::Bucket;
+use async_recursion::async_recursion;
+use aws_config::credential_process::CredentialProcessProvider;
+use aws_config::default_provider::credentials::DefaultCredentialsChain;
+use aws_config::default_provider::region::DefaultRegionChain;
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::{
+    Builder, Credentials, IdentityCache, Region, SharedCredentialsProvider,
+};
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::types::{Bucket, Object};
 use chrono::{DateTime, TimeZone, Utc};
+use futures::{stream, Stream, TryStreamExt};
+use serde::Serialize;
+use super::upload::needs_multipart;
 
+#[derive(Clone)]
 pub struct S3Client {
-    client: AwsS3Client,
+    pub(super) client: AwsS3Client,
 }
 
+#[derive(Serialize)]
 pub struct BucketInfo {
     pub name: String,
     pub creation_date: Option<DateTime<Utc>>,
@@ -20,28 +36,251 @@ impl From<&Bucket> for BucketInfo {
     fn from(bucket: &Bucket) -> Self {
         BucketInfo {
             name: bucket.name().unwrap_or_default().to_string(),
-            creation_date: bucket.creation_date().and_then(|dt| {
-                Utc.timestamp_opt(dt.secs(), dt.subsec_nanos()).single()
-            }),
+            creation_date: bucket.creation_date().and_then(to_utc),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub e_tag: Option<String>,
+    pub storage_class: Option<String>,
+    pub owner: Option<String>,
+}
+
+impl From<&Object> for ObjectInfo {
+    fn from(object: &Object) -> Self {
+        ObjectInfo {
+            key: object.key().unwrap_or_default().to_string(),
+            size: object.size().unwrap_or_default().max(0) as u64,
+            last_modified: object.last_modified().and_then(to_utc),
+            e_tag: object.e_tag().map(String::from),
+            storage_class: object.storage_class().map(|c| c.as_str().to_string()),
+            owner: object
+                .owner()
+                .and_then(|o| o.display_name().or(o.id()))
+                .map(String::from),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HeadObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub e_tag: Option<String>,
+    pub content_type: Option<String>,
+    pub storage_class: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl HeadObjectInfo {
+    fn new(key: &str, resp: &HeadObjectOutput) -> Self {
+        HeadObjectInfo {
+            key: key.to_string(),
+            size: resp.content_length().unwrap_or_default().max(0) as u64,
+            last_modified: resp.last_modified().and_then(to_utc),
+            e_tag: resp.e_tag().map(String::from),
+            content_type: resp.content_type().map(String::from),
+            storage_class: resp.storage_class().map(|c| c.as_str().to_string()),
+            metadata: resp
+                .metadata()
+                .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Upload,
+    Download,
+    Copy,
+    Move,
+    /// Removal of an extraneous destination entry during a sync.
+    Delete,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+            Direction::Copy => "copy",
+            Direction::Move => "move",
+            Direction::Delete => "delete",
+        }
+    }
+}
+
+/// A single PutObject or CopyObject request cannot carry more than this.
+pub const MAX_PUT_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Tuning knobs for multipart transfers.
+#[derive(Clone, Copy, Debug)]
+pub struct TransferOptions {
+    /// Files at or above this size are transferred in parts.
+    pub multipart_threshold: u64,
+    pub part_size: u64,
+    /// Maximum number of parts in flight at once.
+    pub concurrency: usize,
+    /// Keep a local checkpoint so interrupted transfers can be resumed.
+    pub resumable: bool,
+}
+
+/// Options for `sync`.
+#[derive(Clone, Copy, Debug)]
+pub struct SyncOptions {
+    pub transfer: TransferOptions,
+    /// Remove destination entries that do not exist at the source.
+    pub delete: bool,
+    /// Compare MD5 checksums with ETags instead of modification times.
+    pub checksum: bool,
+    /// Keep a state file in the local directory and trust it for files that
+    /// have not changed since the last sync.
+    pub state: bool,
+    /// Ignore the existing state file and rebuild it from a full comparison.
+    pub rebuild_state: bool,
+}
+
+/// Options for server-side copies.
+#[derive(Clone, Copy, Debug)]
+pub struct CopyOptions {
+    pub transfer: TransferOptions,
+    /// Carry the source's tags over, at the cost of a GetObjectTagging
+    /// request for multipart copies and copies between endpoints.
+    pub tags: bool,
+}
+
+/// Outcome of a single-object transfer.
+#[derive(Serialize)]
+pub struct TransferSummary {
+    pub direction: Direction,
+    pub bucket: String,
+    pub key: String,
+    /// The local file, or the `s3://` source URL of a copy.
+    pub path: String,
+    pub bytes: u64,
+    /// Set when this transfer failed as part of a recursive operation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Outcome of deleting one key (or one version of it) in a batch.
+#[derive(Serialize)]
+pub struct DeletedObject {
+    pub bucket: String,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DeletedObject {
+    pub fn failed(
+        bucket: &str,
+        key: &str,
+        version_id: Option<String>,
+        error: anyhow::Error,
+    ) -> Self {
+        DeletedObject {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            version_id,
+            error: Some(format!("{:#}", error)),
         }
     }
 }
 
+fn to_utc(dt: &aws_sdk_s3::primitives::DateTime) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(dt.secs(), dt.subsec_nanos()).single()
+}
+
+/// The credentials of the role in `config.assume_role`, those printed by the
+/// `credential_process`, static keys, or those of the AWS default chain.
+#[async_recursion]
+async fn credentials_provider(config: &AppConfig) -> SharedCredentialsProvider {
+    if let Some(role) = &config.assume_role {
+        let mut sts = aws_config::defaults(BehaviorVersion::latest())
+            .region(resolve_region(config).await)
+            .credentials_provider(credentials_provider(&role.source).await);
+        if let Some(endpoint_url) = &role.sts_endpoint_url {
+            sts = sts.endpoint_url(endpoint_url);
+        }
+        let mut provider = AssumeRoleProvider::builder(&role.role_arn)
+            .session_name("rs3")
+            .configure(&sts.load().await);
+        if let Some(external_id) = &role.external_id {
+            provider = provider.external_id(external_id);
+        }
+        if let Some(duration) = role.duration {
+            provider = provider.session_length(duration);
+        }
+        return SharedCredentialsProvider::new(provider.build().await);
+    }
+    if let Some(command) = &config.credential_process {
+        return SharedCredentialsProvider::new(CredentialProcessProvider::new(command.clone()));
+    }
+    if let (Some(access_key), Some(secret_key)) = (&config.access_key, &config.secret_key) {
+        let token = config.session_token.clone();
+        return SharedCredentialsProvider::new(Credentials::new(
+            access_key, secret_key, token, None, "rs3",
+        ));
+    }
+    let mut chain = DefaultCredentialsChain::builder();
+    if let Some(profile) = &config.aws_profile {
+        chain = chain.profile_name(profile);
+    }
+    SharedCredentialsProvider::new(chain.build().await)
+}
+
+/// The configured region, or the one from `AWS_REGION` or the shared AWS
+/// config, or else `us-east-1`, which S3-compatible stores generally accept.
+async fn resolve_region(config: &AppConfig) -> Region {
+    if let Some(region) = &config.region {
+        return Region::new(region.clone());
+    }
+    let mut chain = DefaultRegionChain::builder();
+    if let Some(profile) = &config.aws_profile {
+        chain = chain.profile_name(profile);
+    }
+    chain
+        .build()
+        .region()
+        .await
+        .unwrap_or_else(|| Region::new("us-east-1"))
+}
+
+/// A single entry of an object listing.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ListEntry {
+    /// A common prefix rolled up by the delimiter, e.g. `logs/2024/`.
+    Prefix { prefix: String },
+    Object(ObjectInfo),
+}
+
 impl S3Client {
-    pub fn new(config: &AppConfig) -> Result<Self> {
-        let cred = Credentials::new(
-            &config.access_key,
-            &config.secret_key,
-            None,
-            None,
-            "rs3",
-        );
-        let client_config = Builder::new().behavior_version_latest()
-            .endpoint_url(&config.endpoint_url)
+    /// Builds a client from `config`, falling back to the AWS default
+    /// credential and region chains for whatever it leaves out.
+    pub async fn new(config: &AppConfig) -> Result<Self> {
+        let mut builder = Builder::new().behavior_version_latest()
             .force_path_style(config.path_style)
-            .region(Region::new(config.region.clone()))
-            .credentials_provider(cred).build();
-        let client = Client::from_conf(client_config);
+            .region(resolve_region(config).await)
+            // Temporary credentials are reused until shortly before they
+            // expire, then fetched again.
+            .identity_cache(IdentityCache::lazy().build())
+            .credentials_provider(credentials_provider(config).await);
+        if let Some(endpoint_url) = &config.endpoint_url {
+            builder = builder.endpoint_url(endpoint_url);
+        }
+        let client = Client::from_conf(builder.build());
 
         // 另一种初始化方式：无法配置path_style
         // let sdk_config = SdkConfig::builder()
@@ -78,44 +317,122 @@ impl S3Client {
         Ok(())
     }
 
-    pub async fn list_objects(&self, bucket: &str, prefix: Option<&str>) -> Result<Vec<String>> {
-        let mut req = self.client.list_objects_v2().bucket(bucket);
-        if let Some(p) = prefix {
-            req = req.prefix(p);
-        }
-        let resp = req.send().await?;
-        Ok(resp
-            .contents()
-            // .unwrap_or_default()
-            .iter()
-            .filter_map(|obj| obj.key().map(String::from))
-            .collect())
+    /// Streams the entries under `prefix`, following continuation tokens.
+    ///
+    /// With a `delimiter`, keys sharing a segment after the prefix are rolled up
+    /// into [`ListEntry::Prefix`] entries; without one every key is listed flat.
+    /// Pages are requested lazily as the stream is polled, so memory use stays
+    /// bounded no matter how many objects the bucket holds.
+    pub fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: Option<&str>,
+        delimiter: Option<&str>,
+    ) -> impl Stream<Item = Result<ListEntry>> {
+        self.list_objects_pages(bucket, prefix, delimiter)
+            .map_ok(|page| {
+                let prefixes = page
+                    .common_prefixes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|p| p.prefix)
+                    .map(|prefix| ListEntry::Prefix { prefix });
+                let objects = page
+                    .contents
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|obj| ListEntry::Object(ObjectInfo::from(obj)))
+                    .collect::<Vec<_>>();
+                stream::iter(prefixes.chain(objects).map(Ok))
+            })
+            .try_flatten()
     }
 
-    pub async fn upload_object(&self, bucket: &str, key: &str, file_path: &str) -> Result<()> {
-        let body =
-            ByteStream::from_path(std::path::Path::new(file_path)).await?;
-        self.client
-            .put_object()
+    /// Streams raw ListObjectsV2 pages under `prefix`.
+    pub fn list_objects_pages(
+        &self,
+        bucket: &str,
+        prefix: Option<&str>,
+        delimiter: Option<&str>,
+    ) -> impl Stream<Item = Result<ListObjectsV2Output>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
             .bucket(bucket)
-            .key(key)
-            .body(body)
-            .send()
-            .await?;
-        Ok(())
+            .set_prefix(prefix.map(String::from))
+            .set_delimiter(delimiter.map(String::from))
+            .fetch_owner(true)
+            .into_paginator()
+            .send();
+        stream::poll_fn(move |cx| pages.poll_next(cx)).map_err(anyhow::Error::from)
     }
 
-    pub async fn download_object(&self, bucket: &str, key: &str, file_path: &str) -> Result<()> {
+    pub async fn head_object(&self, bucket: &str, key: &str) -> Result<HeadObjectInfo> {
         let resp = self
             .client
-            .get_object()
+            .head_object()
             .bucket(bucket)
             .key(key)
             .send()
             .await?;
-        let body = resp.body.collect().await?;
-        std::fs::write(file_path, body.into_bytes())?;
-        Ok(())
+        Ok(HeadObjectInfo::new(key, &resp))
+    }
+
+    /// Uploads a file, switching to multipart from `options.multipart_threshold`
+    /// on and above the 5 GiB PutObject limit.
+    pub async fn upload_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        file_path: &str,
+        options: &TransferOptions,
+    ) -> Result<TransferSummary> {
+        let path = std::path::Path::new(file_path);
+        if path.is_dir() {
+            bail!("{} is a directory, use --recursive to upload it", file_path);
+        }
+        let bytes = std::fs::metadata(path)?.len();
+        if needs_multipart(bytes, options.multipart_threshold) {
+            self.upload_multipart(bucket, key, path, bytes, options).await?;
+        } else {
+            let body = ByteStream::from_path(path).await?;
+            self.client
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(body)
+                .send()
+                .await?;
+        }
+        Ok(TransferSummary {
+            direction: Direction::Upload,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            path: file_path.to_string(),
+            bytes,
+            error: None,
+        })
+    }
+
+    /// Downloads an object to `file_path`, never leaving a partial file there.
+    pub async fn download_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        file_path: &str,
+        options: &TransferOptions,
+    ) -> Result<TransferSummary> {
+        let bytes = self
+            .download_file(bucket, key, std::path::Path::new(file_path), options)
+            .await?;
+        Ok(TransferSummary {
+            direction: Direction::Download,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            path: file_path.to_string(),
+            bytes,
+            error: None,
+        })
     }
 
     pub async fn delete_object(&self, bucket: &str, key: &str) -> Result<()> {
@@ -0,0 +1,606 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_sdk_s3::operation::create_multipart_upload::builders::CreateMultipartUploadFluentBuilder;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedPart, TaggingDirective};
+use futures::{future, Stream, StreamExt};
+use log::info;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use super::client::{CopyOptions, Direction, ListEntry, S3Client, TransferSummary};
+use super::upload::{dir_prefix, effective_part_size, needs_multipart, part_ranges};
+use crate::utils::{format_size, run_concurrent};
+
+/// Characters left as-is in the `x-amz-copy-source` header; everything else
+/// in the key is percent-encoded.
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+impl S3Client {
+    /// Copies an object server-side, so the data never passes through this
+    /// machine.
+    ///
+    /// Objects at or above `options.transfer.multipart_threshold`, and any
+    /// object over the 5 GiB CopyObject limit, are copied as a multipart
+    /// upload whose parts are UploadPartCopy requests for byte ranges of the
+    /// source, at most `options.transfer.concurrency` at a time. Every part is
+    /// pinned to the source ETag seen up front.
+    ///
+    /// The copy keeps the source's user metadata and content headers either
+    /// way, and its tags unless `options.tags` is unset.
+    pub async fn copy_object(
+        &self,
+        src_bucket: &str,
+        src_key: &str,
+        dst_bucket: &str,
+        dst_key: &str,
+        options: &CopyOptions,
+    ) -> Result<TransferSummary> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(src_bucket)
+            .key(src_key)
+            .send()
+            .await?;
+        let bytes = head.content_length().unwrap_or_default().max(0) as u64;
+        let source = copy_source(src_bucket, src_key);
+
+        if needs_multipart(bytes, options.transfer.multipart_threshold) {
+            let upload_id = self
+                .create_copy_upload(src_bucket, src_key, dst_bucket, dst_key, &head, options)
+                .await?;
+            info!(
+                "Copying {} to s3://{}/{} in parts, upload id {}",
+                format_size(bytes),
+                dst_bucket,
+                dst_key,
+                upload_id
+            );
+            let e_tag = head.e_tag().unwrap_or_default();
+            let result = self
+                .copy_parts(
+                    &source, e_tag, dst_bucket, dst_key, &upload_id, bytes, options,
+                )
+                .await;
+            let completed = match result {
+                Ok(parts) => {
+                    self.complete_multipart(dst_bucket, dst_key, &upload_id, parts)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = completed {
+                self.abort_multipart(dst_bucket, dst_key, &upload_id).await;
+                return Err(e);
+            }
+        } else {
+            let mut request = self
+                .client
+                .copy_object()
+                .copy_source(source)
+                .bucket(dst_bucket)
+                .key(dst_key);
+            if !options.tags {
+                // Replacing the tags with none drops them.
+                request = request.tagging_directive(TaggingDirective::Replace);
+            }
+            request.send().await?;
+        }
+        Ok(TransferSummary {
+            direction: Direction::Copy,
+            bucket: dst_bucket.to_string(),
+            key: dst_key.to_string(),
+            path: format!("s3://{}/{}", src_bucket, src_key),
+            bytes,
+            error: None,
+        })
+    }
+
+    /// Copies an object from another endpoint by streaming its body through
+    /// memory, for when source and destination cannot see each other.
+    ///
+    /// Objects below `options.transfer.multipart_threshold` are streamed from
+    /// one GetObject into one PutObject, as long as they fit in one. Larger
+    /// ones become a multipart upload fed by ranged GETs of the source, so at
+    /// most `options.transfer.concurrency` parts are held in memory at once.
+    /// Nothing is written to local disk, and every GET is pinned to the source
+    /// ETag seen up front.
+    ///
+    /// User metadata and content headers are carried over from the source, and
+    /// tags as well with `options.tags`.
+    pub async fn copy_object_from(
+        &self,
+        source: &S3Client,
+        src_bucket: &str,
+        src_key: &str,
+        dst_bucket: &str,
+        dst_key: &str,
+        options: &CopyOptions,
+    ) -> Result<TransferSummary> {
+        let head = source
+            .client
+            .head_object()
+            .bucket(src_bucket)
+            .key(src_key)
+            .send()
+            .await?;
+        let bytes = head.content_length().unwrap_or_default().max(0) as u64;
+        let e_tag = head.e_tag().filter(|e| !e.is_empty()).map(String::from);
+        let attributes = source
+            .object_attributes(src_bucket, src_key, &head, options.tags)
+            .await?;
+
+        if !needs_multipart(bytes, options.transfer.multipart_threshold) {
+            let object = source
+                .client
+                .get_object()
+                .bucket(src_bucket)
+                .key(src_key)
+                .set_if_match(e_tag)
+                .send()
+                .await?;
+            let request = self
+                .client
+                .put_object()
+                .bucket(dst_bucket)
+                .key(dst_key)
+                .content_length(bytes as i64)
+                .body(object.body);
+            attributes.apply_to_put(request).send().await?;
+        } else {
+            let request = self
+                .client
+                .create_multipart_upload()
+                .bucket(dst_bucket)
+                .key(dst_key);
+            let upload_id = attributes
+                .apply_to_upload(request)
+                .send()
+                .await?
+                .upload_id
+                .context("CreateMultipartUpload returned no upload id")?;
+            let part_size = effective_part_size(bytes, options.transfer.part_size);
+            let jobs = part_ranges(bytes, part_size).map(|(part_number, offset, length)| {
+                let (source, target) = (source.client.clone(), self.client.clone());
+                let (src_bucket, src_key) = (src_bucket.to_string(), src_key.to_string());
+                let (dst_bucket, dst_key) = (dst_bucket.to_string(), dst_key.to_string());
+                let (upload_id, e_tag) = (upload_id.clone(), e_tag.clone());
+                async move {
+                    let body = source
+                        .get_object()
+                        .bucket(src_bucket)
+                        .key(src_key)
+                        .range(format!("bytes={}-{}", offset, offset + length - 1))
+                        .set_if_match(e_tag)
+                        .send()
+                        .await
+                        .with_context(|| format!("Failed to read part {}", part_number))?
+                        .body
+                        .collect()
+                        .await?
+                        .into_bytes();
+                    let resp = target
+                        .upload_part()
+                        .bucket(dst_bucket)
+                        .key(dst_key)
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .body(ByteStream::from(body))
+                        .send()
+                        .await
+                        .with_context(|| format!("Failed to upload part {}", part_number))?;
+                    Ok(CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(resp.e_tag)
+                        .build())
+                }
+            });
+            let completed = match run_concurrent(options.transfer.concurrency, jobs).await {
+                Ok(parts) => {
+                    self.complete_multipart(dst_bucket, dst_key, &upload_id, parts)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = completed {
+                self.abort_multipart(dst_bucket, dst_key, &upload_id).await;
+                return Err(e);
+            }
+        }
+        Ok(TransferSummary {
+            direction: Direction::Copy,
+            bucket: dst_bucket.to_string(),
+            key: dst_key.to_string(),
+            path: format!("s3://{}/{}", src_bucket, src_key),
+            bytes,
+            error: None,
+        })
+    }
+
+    /// Starts the multipart upload for a copy, carrying over the source's
+    /// metadata and content headers, and its tags when `options.tags` is set.
+    async fn create_copy_upload(
+        &self,
+        src_bucket: &str,
+        src_key: &str,
+        dst_bucket: &str,
+        dst_key: &str,
+        head: &HeadObjectOutput,
+        options: &CopyOptions,
+    ) -> Result<String> {
+        let attributes = self
+            .object_attributes(src_bucket, src_key, head, options.tags)
+            .await?;
+        let request = self
+            .client
+            .create_multipart_upload()
+            .bucket(dst_bucket)
+            .key(dst_key);
+        attributes
+            .apply_to_upload(request)
+            .send()
+            .await?
+            .upload_id
+            .context("CreateMultipartUpload returned no upload id")
+    }
+
+    /// The metadata and content headers of an object from its `head`, plus
+    /// its tags if `tags` is set.
+    async fn object_attributes(
+        &self,
+        bucket: &str,
+        key: &str,
+        head: &HeadObjectOutput,
+        tags: bool,
+    ) -> Result<ObjectAttributes> {
+        let tagging = if tags {
+            self.object_tagging(bucket, key).await?
+        } else {
+            None
+        };
+        Ok(ObjectAttributes {
+            metadata: head.metadata().cloned(),
+            content_type: head.content_type().map(String::from),
+            content_encoding: head.content_encoding().map(String::from),
+            content_disposition: head.content_disposition().map(String::from),
+            content_language: head.content_language().map(String::from),
+            cache_control: head.cache_control().map(String::from),
+            tagging,
+        })
+    }
+
+    /// The tag set of an object, URL-encoded for the `x-amz-tagging` header.
+    async fn object_tagging(&self, bucket: &str, key: &str) -> Result<Option<String>> {
+        let tags = self
+            .client
+            .get_object_tagging()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await?;
+        let tagging = tags
+            .tag_set()
+            .iter()
+            .map(|tag| {
+                format!(
+                    "{}={}",
+                    utf8_percent_encode(tag.key(), NON_ALPHANUMERIC),
+                    utf8_percent_encode(tag.value(), NON_ALPHANUMERIC)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        Ok((!tagging.is_empty()).then_some(tagging))
+    }
+
+    /// Copies `size` bytes of `source` into the upload as concurrent ranged
+    /// UploadPartCopy requests.
+    #[allow(clippy::too_many_arguments)]
+    async fn copy_parts(
+        &self,
+        source: &str,
+        e_tag: &str,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        size: u64,
+        options: &CopyOptions,
+    ) -> Result<Vec<CompletedPart>> {
+        let part_size = effective_part_size(size, options.transfer.part_size);
+        let jobs = part_ranges(size, part_size).map(|(part_number, offset, length)| {
+            let client = self.client.clone();
+            let (source, e_tag) = (source.to_string(), e_tag.to_string());
+            let (bucket, key, upload_id) =
+                (bucket.to_string(), key.to_string(), upload_id.to_string());
+            async move {
+                let resp = client
+                    .upload_part_copy()
+                    .copy_source(source)
+                    .set_copy_source_if_match((!e_tag.is_empty()).then_some(e_tag))
+                    .copy_source_range(format!("bytes={}-{}", offset, offset + length - 1))
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .send()
+                    .await
+                    .with_context(|| format!("Failed to copy part {}", part_number))?;
+                let e_tag = resp
+                    .copy_part_result()
+                    .and_then(|r| r.e_tag())
+                    .unwrap_or_default();
+                Ok(CompletedPart::builder()
+                    .part_number(part_number)
+                    .e_tag(e_tag)
+                    .build())
+            }
+        });
+        run_concurrent(options.transfer.concurrency, jobs).await
+    }
+
+    /// Moves an object: copies it server-side, confirms the copy with a HEAD of
+    /// the destination, and only then deletes the source. On any failure the
+    /// source is left in place.
+    pub async fn move_object(
+        &self,
+        src_bucket: &str,
+        src_key: &str,
+        dst_bucket: &str,
+        dst_key: &str,
+        options: &CopyOptions,
+    ) -> Result<TransferSummary> {
+        if src_bucket == dst_bucket && src_key == dst_key {
+            bail!("Cannot move s3://{}/{} onto itself", src_bucket, src_key);
+        }
+        let mut summary = self
+            .copy_object(src_bucket, src_key, dst_bucket, dst_key, options)
+            .await?;
+        let copied = self
+            .client
+            .head_object()
+            .bucket(dst_bucket)
+            .key(dst_key)
+            .send()
+            .await
+            .context("Failed to confirm the copy, source kept")?;
+        let copied_size = copied.content_length().unwrap_or_default().max(0) as u64;
+        if copied_size != summary.bytes {
+            bail!(
+                "Copy is {} bytes but the source is {}, source kept",
+                copied_size,
+                summary.bytes
+            );
+        }
+        self.delete_object(src_bucket, src_key)
+            .await
+            .context("Copied, but failed to delete the source")?;
+        summary.direction = Direction::Move;
+        Ok(summary)
+    }
+
+    /// Copies every object under `src_prefix` to `dst_prefix`, keeping the key
+    /// hierarchy below the source prefix's last `/` like
+    /// [`S3Client::download_prefix`] does. At most
+    /// `options.transfer.concurrency` copies run at once and a summary is
+    /// yielded for each one.
+    ///
+    /// Fails up front when the destination lies inside the source prefix of
+    /// the same bucket, since the listing would pick up the copies again.
+    pub fn copy_prefix(
+        &self,
+        src_bucket: &str,
+        src_prefix: &str,
+        dst_bucket: &str,
+        dst_prefix: &str,
+        options: &CopyOptions,
+    ) -> Result<impl Stream<Item = TransferSummary>> {
+        if src_bucket == dst_bucket && dir_prefix(dst_prefix).starts_with(&dir_prefix(src_prefix)) {
+            bail!(
+                "Cannot copy s3://{}/{} into itself at s3://{}/{}",
+                src_bucket,
+                src_prefix,
+                dst_bucket,
+                dst_prefix
+            );
+        }
+        Ok(self.copy_objects_under(
+            src_bucket, src_prefix, dst_bucket, dst_prefix, options, false,
+        ))
+    }
+
+    /// Moves every object under `src_prefix` to `dst_prefix` with
+    /// [`S3Client::move_object`], mapping keys like
+    /// [`S3Client::copy_prefix`]. Each summary tells whether that key was
+    /// moved; keys that failed keep their source.
+    ///
+    /// Fails up front when the destination lies inside the source prefix of
+    /// the same bucket, since the listing would pick up the moved keys again.
+    pub fn move_prefix(
+        &self,
+        src_bucket: &str,
+        src_prefix: &str,
+        dst_bucket: &str,
+        dst_prefix: &str,
+        options: &CopyOptions,
+    ) -> Result<impl Stream<Item = TransferSummary>> {
+        if src_bucket == dst_bucket && dir_prefix(dst_prefix).starts_with(&dir_prefix(src_prefix)) {
+            bail!(
+                "Cannot move s3://{}/{} into itself at s3://{}/{}",
+                src_bucket,
+                src_prefix,
+                dst_bucket,
+                dst_prefix
+            );
+        }
+        Ok(self.copy_objects_under(
+            src_bucket, src_prefix, dst_bucket, dst_prefix, options, true,
+        ))
+    }
+
+    fn copy_objects_under(
+        &self,
+        src_bucket: &str,
+        src_prefix: &str,
+        dst_bucket: &str,
+        dst_prefix: &str,
+        options: &CopyOptions,
+        remove_source: bool,
+    ) -> impl Stream<Item = TransferSummary> {
+        let direction = if remove_source {
+            Direction::Move
+        } else {
+            Direction::Copy
+        };
+        let base = src_prefix[..src_prefix.rfind('/').map_or(0, |i| i + 1)].to_string();
+        let (client, src_bucket, dst_bucket, dst_prefix, options) = (
+            self.clone(),
+            src_bucket.to_string(),
+            dst_bucket.to_string(),
+            dir_prefix(dst_prefix),
+            *options,
+        );
+        let failed_bucket = dst_bucket.clone();
+        // A destination next to the source, e.g. `logs-archive/` for `logs`,
+        // still matches the listing prefix; its keys are skipped so the copies
+        // are not copied again.
+        let skipped = (src_bucket == dst_bucket && dst_prefix.starts_with(src_prefix))
+            .then(|| dst_prefix.clone());
+        self.list_objects(&src_bucket, Some(src_prefix), None)
+            .filter(move |entry| {
+                let skip = match (entry, &skipped) {
+                    (Ok(ListEntry::Object(object)), Some(skipped)) => {
+                        object.key.starts_with(skipped.as_str())
+                    }
+                    _ => false,
+                };
+                future::ready(!skip)
+            })
+            .map(move |entry| {
+                let (client, src_bucket, dst_bucket, base, dst_prefix) = (
+                    client.clone(),
+                    src_bucket.clone(),
+                    dst_bucket.clone(),
+                    base.clone(),
+                    dst_prefix.clone(),
+                );
+                tokio::spawn(async move {
+                    let object = match entry {
+                        Ok(ListEntry::Object(object)) => object,
+                        Ok(ListEntry::Prefix { .. }) => return None,
+                        Err(e) => {
+                            return Some(failed_copy(
+                                direction,
+                                &src_bucket,
+                                "",
+                                &dst_bucket,
+                                "",
+                                e,
+                            ))
+                        }
+                    };
+                    let relative = object
+                        .key
+                        .strip_prefix(base.as_str())
+                        .unwrap_or(&object.key);
+                    let dst_key = format!("{}{}", dst_prefix, relative);
+                    let (src_key, dst_key) = (object.key.as_str(), dst_key.as_str());
+                    let result = if remove_source {
+                        client
+                            .move_object(&src_bucket, src_key, &dst_bucket, dst_key, &options)
+                            .await
+                    } else {
+                        client
+                            .copy_object(&src_bucket, src_key, &dst_bucket, dst_key, &options)
+                            .await
+                    };
+                    Some(result.unwrap_or_else(|e| {
+                        failed_copy(direction, &src_bucket, src_key, &dst_bucket, dst_key, e)
+                    }))
+                })
+            })
+            .buffer_unordered(options.transfer.concurrency)
+            .filter_map(move |joined| {
+                future::ready(joined.unwrap_or_else(|e| {
+                    Some(failed_copy(
+                        direction,
+                        "",
+                        "",
+                        &failed_bucket,
+                        "",
+                        anyhow!(e),
+                    ))
+                }))
+            })
+    }
+}
+
+/// The metadata, content headers and tags of a source object, to be applied
+/// to its copy.
+struct ObjectAttributes {
+    metadata: Option<HashMap<String, String>>,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    content_disposition: Option<String>,
+    content_language: Option<String>,
+    cache_control: Option<String>,
+    /// URL-encoded `key=value&...` tag set.
+    tagging: Option<String>,
+}
+
+impl ObjectAttributes {
+    fn apply_to_upload(
+        self,
+        request: CreateMultipartUploadFluentBuilder,
+    ) -> CreateMultipartUploadFluentBuilder {
+        request
+            .set_metadata(self.metadata)
+            .set_content_type(self.content_type)
+            .set_content_encoding(self.content_encoding)
+            .set_content_disposition(self.content_disposition)
+            .set_content_language(self.content_language)
+            .set_cache_control(self.cache_control)
+            .set_tagging(self.tagging)
+    }
+
+    fn apply_to_put(self, request: PutObjectFluentBuilder) -> PutObjectFluentBuilder {
+        request
+            .set_metadata(self.metadata)
+            .set_content_type(self.content_type)
+            .set_content_encoding(self.content_encoding)
+            .set_content_disposition(self.content_disposition)
+            .set_content_language(self.content_language)
+            .set_cache_control(self.cache_control)
+            .set_tagging(self.tagging)
+    }
+}
+
+/// The `bucket/key` value of the copy source header, with the key URL-encoded.
+fn copy_source(bucket: &str, key: &str) -> String {
+    format!("{}/{}", bucket, utf8_percent_encode(key, COPY_SOURCE))
+}
+
+fn failed_copy(
+    direction: Direction,
+    src_bucket: &str,
+    src_key: &str,
+    dst_bucket: &str,
+    dst_key: &str,
+    error: anyhow::Error,
+) -> TransferSummary {
+    TransferSummary {
+        direction,
+        bucket: dst_bucket.to_string(),
+        key: dst_key.to_string(),
+        path: format!("s3://{}/{}", src_bucket, src_key),
+        bytes: 0,
+        error: Some(format!("{:#}", error)),
+    }
+}
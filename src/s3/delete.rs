@@ -0,0 +1,254 @@
+use anyhow::Result;
+use aws_sdk_s3::types::{Delete, ObjectIdentifier};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+
+use super::client::{DeletedObject, S3Client};
+use crate::utils::run_concurrent;
+
+/// DeleteObjects accepts at most this many keys per request.
+pub const MAX_DELETE_BATCH: usize = 1000;
+
+impl S3Client {
+    /// Deletes every object under `prefix`.
+    ///
+    /// Each listing page (up to 1000 keys) becomes one DeleteObjects request and
+    /// at most `concurrency` requests run at once. A result is yielded for every
+    /// key, including the per-key errors S3 reports inside a successful response.
+    pub fn delete_prefix(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        concurrency: usize,
+    ) -> impl Stream<Item = DeletedObject> {
+        let (client, bucket_name, prefix_name) =
+            (self.clone(), bucket.to_string(), prefix.to_string());
+        let (failed_bucket, failed_prefix) = (bucket_name.clone(), prefix_name.clone());
+        self.list_objects_pages(bucket, Some(prefix), None)
+            .map(move |page| {
+                let (client, bucket, prefix) =
+                    (client.clone(), bucket_name.clone(), prefix_name.clone());
+                tokio::spawn(async move {
+                    let page = match page {
+                        Ok(page) => page,
+                        Err(e) => return vec![DeletedObject::failed(&bucket, &prefix, None, e)],
+                    };
+                    let objects = page
+                        .contents()
+                        .iter()
+                        .filter_map(|o| o.key().map(|k| (k.to_string(), None)))
+                        .collect();
+                    client.delete_batch(&bucket, objects).await
+                })
+            })
+            .buffer_unordered(concurrency.max(1))
+            .flat_map(move |joined| {
+                stream::iter(joined.unwrap_or_else(|e| {
+                    let error = anyhow::Error::new(e);
+                    vec![DeletedObject::failed(
+                        &failed_bucket,
+                        &failed_prefix,
+                        None,
+                        error,
+                    )]
+                }))
+            })
+    }
+
+    /// Deletes every object version and delete marker in `bucket`, so that a
+    /// versioned bucket can be removed. Unversioned buckets list each object
+    /// once with a `null` version.
+    pub fn empty_bucket(
+        &self,
+        bucket: &str,
+        concurrency: usize,
+    ) -> impl Stream<Item = DeletedObject> {
+        let (client, bucket_name) = (self.clone(), bucket.to_string());
+        let failed_bucket = bucket_name.clone();
+        self.list_versions_pages(bucket)
+            .map(move |page| {
+                let (client, bucket) = (client.clone(), bucket_name.clone());
+                tokio::spawn(async move {
+                    match page {
+                        Ok(versions) => client.delete_batch(&bucket, versions).await,
+                        Err(e) => vec![DeletedObject::failed(&bucket, "", None, e)],
+                    }
+                })
+            })
+            .buffer_unordered(concurrency.max(1))
+            .flat_map(move |joined| {
+                stream::iter(joined.unwrap_or_else(|e| {
+                    let error = anyhow::Error::new(e);
+                    vec![DeletedObject::failed(&failed_bucket, "", None, error)]
+                }))
+            })
+    }
+
+    /// Counts the object versions and delete markers in `bucket`.
+    pub async fn count_versions(&self, bucket: &str) -> Result<u64> {
+        self.list_versions_pages(bucket)
+            .try_fold(0, |count, versions| async move {
+                Ok(count + versions.len() as u64)
+            })
+            .await
+    }
+
+    /// Streams `(key, version_id)` pairs for every object version and delete
+    /// marker, one ListObjectVersions page at a time.
+    fn list_versions_pages(
+        &self,
+        bucket: &str,
+    ) -> impl Stream<Item = Result<Vec<(String, Option<String>)>>> {
+        let (client, bucket) = (self.client.clone(), bucket.to_string());
+        stream::try_unfold(Some((None, None)), move |markers| {
+            let (client, bucket) = (client.clone(), bucket.clone());
+            async move {
+                let Some((key_marker, version_id_marker)) = markers else {
+                    return Ok(None);
+                };
+                let page = client
+                    .list_object_versions()
+                    .bucket(bucket)
+                    .set_key_marker(key_marker)
+                    .set_version_id_marker(version_id_marker)
+                    .send()
+                    .await?;
+                let versions = page
+                    .versions()
+                    .iter()
+                    .map(|v| (v.key(), v.version_id()))
+                    .chain(
+                        page.delete_markers()
+                            .iter()
+                            .map(|m| (m.key(), m.version_id())),
+                    )
+                    .filter_map(|(key, version_id)| {
+                        Some((key?.to_string(), version_id.map(String::from)))
+                    })
+                    .collect();
+                let next = page.is_truncated().unwrap_or(false).then(|| {
+                    (
+                        page.next_key_marker().map(String::from),
+                        page.next_version_id_marker().map(String::from),
+                    )
+                });
+                Ok(Some((versions, next)))
+            }
+        })
+    }
+
+    /// Lists in-progress multipart uploads as `(key, upload_id)` pairs.
+    pub async fn list_multipart_uploads(&self, bucket: &str) -> Result<Vec<(String, String)>> {
+        let mut uploads = Vec::new();
+        let (mut key_marker, mut upload_id_marker) = (None, None);
+        loop {
+            let page = self
+                .client
+                .list_multipart_uploads()
+                .bucket(bucket)
+                .set_key_marker(key_marker)
+                .set_upload_id_marker(upload_id_marker)
+                .send()
+                .await?;
+            uploads.extend(
+                page.uploads()
+                    .iter()
+                    .filter_map(|u| Some((u.key()?.to_string(), u.upload_id()?.to_string()))),
+            );
+            if !page.is_truncated().unwrap_or(false) {
+                return Ok(uploads);
+            }
+            key_marker = page.next_key_marker().map(String::from);
+            upload_id_marker = page.next_upload_id_marker().map(String::from);
+        }
+    }
+
+    /// Aborts every in-progress multipart upload in `bucket`, returning how
+    /// many were aborted.
+    pub async fn abort_multipart_uploads(&self, bucket: &str, concurrency: usize) -> Result<usize> {
+        let uploads = self.list_multipart_uploads(bucket).await?;
+        let jobs = uploads.into_iter().map(|(key, upload_id)| {
+            let (client, bucket) = (self.client.clone(), bucket.to_string());
+            async move {
+                client
+                    .abort_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await?;
+                Ok(())
+            }
+        });
+        Ok(run_concurrent(concurrency, jobs).await?.len())
+    }
+
+    /// Deletes `(key, version_id)` pairs with one DeleteObjects request per
+    /// [`MAX_DELETE_BATCH`] keys. A request that fails outright is reported as
+    /// a failure for every key it carried.
+    pub async fn delete_batch(
+        &self,
+        bucket: &str,
+        objects: Vec<(String, Option<String>)>,
+    ) -> Vec<DeletedObject> {
+        let mut results = Vec::with_capacity(objects.len());
+        for batch in objects.chunks(MAX_DELETE_BATCH) {
+            match self.delete_objects(bucket, batch).await {
+                Ok(deleted) => results.extend(deleted),
+                Err(e) => {
+                    let error = format!("{:#}", e);
+                    results.extend(batch.iter().map(|(key, version_id)| DeletedObject {
+                        bucket: bucket.to_string(),
+                        key: key.clone(),
+                        version_id: version_id.clone(),
+                        error: Some(error.clone()),
+                    }));
+                }
+            }
+        }
+        results
+    }
+
+    async fn delete_objects(
+        &self,
+        bucket: &str,
+        objects: &[(String, Option<String>)],
+    ) -> Result<Vec<DeletedObject>> {
+        if objects.is_empty() {
+            return Ok(Vec::new());
+        }
+        let identifiers = objects
+            .iter()
+            .map(|(key, version_id)| {
+                ObjectIdentifier::builder()
+                    .key(key)
+                    .set_version_id(version_id.clone())
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let resp = self
+            .client
+            .delete_objects()
+            .bucket(bucket)
+            .delete(Delete::builder().set_objects(Some(identifiers)).build()?)
+            .send()
+            .await?;
+
+        let deleted = resp.deleted().iter().map(|d| DeletedObject {
+            bucket: bucket.to_string(),
+            key: d.key().unwrap_or_default().to_string(),
+            version_id: d.version_id().map(String::from),
+            error: None,
+        });
+        let failed = resp.errors().iter().map(|e| DeletedObject {
+            bucket: bucket.to_string(),
+            key: e.key().unwrap_or_default().to_string(),
+            version_id: e.version_id().map(String::from),
+            error: Some(format!(
+                "{}: {}",
+                e.code().unwrap_or("Unknown"),
+                e.message().unwrap_or_default()
+            )),
+        });
+        Ok(deleted.chain(failed).collect())
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use futures::{future, Stream, StreamExt};
+use log::{info, warn};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+use super::checkpoint::DownloadCheckpoint;
+use super::client::{Direction, ListEntry, S3Client, TransferOptions, TransferSummary};
+use super::upload::{effective_part_size, part_ranges};
+use crate::utils::{format_size, run_concurrent};
+
+impl S3Client {
+    /// Downloads every object under `prefix` into `dir`, recreating the key
+    /// hierarchy below the prefix's last `/`.
+    ///
+    /// Keys that would resolve outside `dir` (`..` segments, absolute paths,
+    /// empty segments or backslashes) are reported as failures and never
+    /// written. Objects are fetched by at most `options.concurrency` tokio
+    /// tasks and a summary is yielded for each one as it finishes.
+    pub fn download_prefix(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        dir: &Path,
+        options: &TransferOptions,
+    ) -> impl Stream<Item = TransferSummary> {
+        let base = &prefix[..prefix.rfind('/').map_or(0, |i| i + 1)];
+        let keys = self
+            .list_objects(bucket, Some(prefix), None)
+            .filter_map(|entry| {
+                future::ready(match entry {
+                    Ok(ListEntry::Object(object)) => Some(Ok(object.key)),
+                    Ok(ListEntry::Prefix { .. }) => None,
+                    Err(e) => Some(Err(e)),
+                })
+            });
+        self.download_keys(bucket, base, dir, keys, options)
+    }
+
+    /// Downloads each key into `dir` at its path relative to `base`, like
+    /// [`S3Client::download_prefix`]. Listing errors in `keys` are reported as
+    /// failed downloads.
+    pub(super) fn download_keys(
+        &self,
+        bucket: &str,
+        base: &str,
+        dir: &Path,
+        keys: impl Stream<Item = Result<String>> + Send + 'static,
+        options: &TransferOptions,
+    ) -> impl Stream<Item = TransferSummary> {
+        let (client, bucket, base, dir, options) = (
+            self.clone(),
+            bucket.to_string(),
+            base.to_string(),
+            dir.to_path_buf(),
+            *options,
+        );
+        keys.map(move |key| {
+            let (client, bucket, base, dir) =
+                (client.clone(), bucket.clone(), base.clone(), dir.clone());
+            tokio::spawn(async move {
+                let key = match key {
+                    Ok(key) => key,
+                    Err(e) => return Some(failed_download(&bucket, "", &dir, e)),
+                };
+                // Zero-byte "directory" placeholders have nothing to download.
+                if key.ends_with('/') {
+                    return None;
+                }
+                let relative = key.strip_prefix(base.as_str()).unwrap_or(&key);
+                let path = match safe_local_path(&dir, relative) {
+                    Ok(path) => path,
+                    Err(e) => return Some(failed_download(&bucket, &key, &dir, e)),
+                };
+                let result = async {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent).await?;
+                    }
+                    client
+                        .download_object(&bucket, &key, &path.display().to_string(), &options)
+                        .await
+                }
+                .await;
+                Some(result.unwrap_or_else(|e| failed_download(&bucket, &key, &path, e)))
+            })
+        })
+        .buffer_unordered(options.concurrency)
+        .filter_map(|joined| {
+            future::ready(
+                joined.unwrap_or_else(|e| Some(failed_download("", "", Path::new(""), anyhow!(e)))),
+            )
+        })
+    }
+
+    /// Downloads an object into `path` through a temporary `.part` file.
+    ///
+    /// Objects at or above `options.multipart_threshold` are fetched as
+    /// concurrent ranged GETs written at their offsets in a preallocated file;
+    /// smaller ones are streamed in a single request. Either way memory use does
+    /// not depend on the object size, and the destination only appears once the
+    /// whole object has been written and synced.
+    ///
+    /// Every GET is pinned with `If-Match` to the ETag seen up front. When
+    /// `options.resumable` is set, a failed download keeps its `.part` file and
+    /// a checkpoint so re-running the same command continues where it stopped,
+    /// unless the object has changed in the meantime. Returns the object size.
+    pub(super) async fn download_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        options: &TransferOptions,
+    ) -> Result<u64> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await?;
+        let size = head.content_length().unwrap_or_default().max(0) as u64;
+        let e_tag = head.e_tag().unwrap_or_default().to_string();
+        // Ranges follow the multipart upload limits too, so a tiny --part-size
+        // cannot turn a large object into millions of GETs.
+        let part_size = (size >= options.multipart_threshold)
+            .then(|| effective_part_size(size, options.part_size));
+
+        let part_path = part_path(path);
+        let checkpoint_path = DownloadCheckpoint::path_for(path);
+        // Without an ETag there is nothing to pin a resumed download to.
+        let resumable = options.resumable && !e_tag.is_empty();
+        let resumed = if resumable {
+            resume_checkpoint(
+                &checkpoint_path,
+                &part_path,
+                bucket,
+                key,
+                &e_tag,
+                size,
+                part_size,
+            )
+        } else {
+            None
+        };
+        let is_resumed = resumed.is_some();
+        let checkpoint = match resumed {
+            Some(checkpoint) => checkpoint,
+            None => {
+                remove_if_exists(&part_path).await;
+                DownloadCheckpoint {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                    e_tag,
+                    size,
+                    part_size,
+                    parts: Vec::new(),
+                }
+            }
+        };
+        if resumable {
+            checkpoint.save(&checkpoint_path)?;
+        }
+
+        let checkpoint = Arc::new(Mutex::new(checkpoint));
+        let result = match part_size {
+            Some(part_size) => {
+                self.fetch_ranges(
+                    &checkpoint,
+                    &part_path,
+                    part_size,
+                    resumable.then_some(checkpoint_path.as_path()),
+                    options.concurrency,
+                )
+                .await
+            }
+            None => self.stream_to(&checkpoint, &part_path, is_resumed).await,
+        };
+        match result {
+            Ok(()) => {
+                fs::rename(&part_path, path).await?;
+                if resumable {
+                    DownloadCheckpoint::remove(&checkpoint_path);
+                }
+                Ok(size)
+            }
+            Err(e) if resumable => {
+                warn!(
+                    "Download of '{}' was interrupted; re-run the same command to resume",
+                    key
+                );
+                Err(e)
+            }
+            Err(e) => {
+                remove_if_exists(&part_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Streams the object in one GET, appending to `path` when resuming.
+    async fn stream_to(
+        &self,
+        checkpoint: &Arc<Mutex<DownloadCheckpoint>>,
+        path: &Path,
+        resume: bool,
+    ) -> Result<()> {
+        let (bucket, key, e_tag, size) = {
+            let cp = checkpoint.lock().unwrap();
+            (
+                cp.bucket.clone(),
+                cp.key.clone(),
+                pinned(&cp.e_tag),
+                cp.size,
+            )
+        };
+        let offset = if resume {
+            fs::metadata(path).await?.len().min(size)
+        } else {
+            0
+        };
+        let file = if offset > 0 {
+            info!("Resuming download of {} at {}", key, format_size(offset));
+            let mut file = OpenOptions::new().write(true).open(path).await?;
+            file.set_len(offset).await?;
+            file.seek(SeekFrom::End(0)).await?;
+            file
+        } else {
+            File::create(path).await?
+        };
+        let mut writer = BufWriter::new(file);
+        let mut written = offset;
+        if offset < size {
+            let mut resp = self
+                .client
+                .get_object()
+                .bucket(bucket)
+                .key(&key)
+                .set_range((offset > 0).then(|| format!("bytes={}-", offset)))
+                .set_if_match(e_tag)
+                .send()
+                .await?;
+            while let Some(chunk) = resp.body.try_next().await? {
+                writer.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+        }
+        writer.flush().await?;
+        writer.into_inner().sync_all().await?;
+        if written != size {
+            bail!(
+                "Expected {} bytes for '{}' but received {}",
+                size,
+                key,
+                written
+            );
+        }
+        Ok(())
+    }
+
+    /// Fetches the ranges missing from `checkpoint` as concurrent ranged GETs,
+    /// recording each one in the checkpoint file as it completes.
+    async fn fetch_ranges(
+        &self,
+        checkpoint: &Arc<Mutex<DownloadCheckpoint>>,
+        path: &Path,
+        part_size: u64,
+        checkpoint_path: Option<&Path>,
+        concurrency: usize,
+    ) -> Result<()> {
+        let (bucket, key, e_tag, size, done) = {
+            let cp = checkpoint.lock().unwrap();
+            let done: HashSet<i32> = cp.parts.iter().copied().collect();
+            (
+                cp.bucket.clone(),
+                cp.key.clone(),
+                pinned(&cp.e_tag),
+                cp.size,
+                done,
+            )
+        };
+        let file = if done.is_empty() {
+            let file = File::create(path).await?;
+            file.set_len(size).await?;
+            file
+        } else {
+            OpenOptions::new().write(true).open(path).await?
+        };
+
+        info!(
+            "Downloading {} in {} ranges of {} ({} already downloaded)",
+            key,
+            size.div_ceil(part_size),
+            format_size(part_size),
+            done.len()
+        );
+        let jobs = part_ranges(size, part_size)
+            .filter(|(part_number, _, _)| !done.contains(part_number))
+            .map(|(part_number, offset, length)| {
+                let client = self.client.clone();
+                let (bucket, key, e_tag) = (bucket.clone(), key.clone(), e_tag.clone());
+                let path = PathBuf::from(path);
+                let checkpoint = Arc::clone(checkpoint);
+                let checkpoint_path = checkpoint_path.map(PathBuf::from);
+                async move {
+                    let mut resp = client
+                        .get_object()
+                        .bucket(bucket)
+                        .key(key)
+                        .range(format!("bytes={}-{}", offset, offset + length - 1))
+                        .set_if_match(e_tag)
+                        .send()
+                        .await?;
+                    let mut file = OpenOptions::new().write(true).open(&path).await?;
+                    file.seek(SeekFrom::Start(offset)).await?;
+                    let mut writer = BufWriter::new(file);
+                    let mut written = 0;
+                    while let Some(chunk) = resp.body.try_next().await? {
+                        writer.write_all(&chunk).await?;
+                        written += chunk.len() as u64;
+                    }
+                    writer.flush().await?;
+                    if written != length {
+                        bail!(
+                            "Expected {} bytes at offset {} but received {}",
+                            length,
+                            offset,
+                            written
+                        );
+                    }
+                    if let Some(checkpoint_path) = checkpoint_path {
+                        // The range must be durable before it is recorded as done.
+                        writer.into_inner().sync_data().await?;
+                        let mut cp = checkpoint.lock().unwrap();
+                        cp.parts.push(part_number);
+                        cp.save(&checkpoint_path)?;
+                    }
+                    Ok(())
+                }
+            });
+        run_concurrent(concurrency, jobs).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+}
+
+/// Loads the checkpoint for an interrupted download if it can be continued.
+fn resume_checkpoint(
+    checkpoint_path: &Path,
+    part_path: &Path,
+    bucket: &str,
+    key: &str,
+    e_tag: &str,
+    size: u64,
+    part_size: Option<u64>,
+) -> Option<DownloadCheckpoint> {
+    let checkpoint = DownloadCheckpoint::load(checkpoint_path)?;
+    if checkpoint.bucket != bucket || checkpoint.key != key || !part_path.exists() {
+        return None;
+    }
+    if checkpoint.e_tag != e_tag || checkpoint.size != size {
+        warn!(
+            "'{}' changed since the interrupted download, starting over",
+            key
+        );
+        return None;
+    }
+    if checkpoint.part_size != part_size {
+        return None;
+    }
+    Some(checkpoint)
+}
+
+/// Maps a key (relative to the download prefix) to a path inside `dir`.
+///
+/// Rejects anything that could escape `dir` or that has no faithful local
+/// representation instead of trying to repair it.
+pub fn safe_local_path(dir: &Path, relative: &str) -> Result<PathBuf> {
+    if relative.is_empty() {
+        bail!("key has no name below the prefix");
+    }
+    let mut path = dir.to_path_buf();
+    for segment in relative.split('/') {
+        if segment.is_empty() || segment.contains(['\\', '\0']) {
+            bail!(
+                "unsafe key '{}': empty segment or invalid separator",
+                relative
+            );
+        }
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => path.push(name),
+            _ => bail!(
+                "unsafe key '{}': '{}' would escape the target directory",
+                relative,
+                segment
+            ),
+        }
+    }
+    Ok(path)
+}
+
+fn failed_download(bucket: &str, key: &str, path: &Path, error: anyhow::Error) -> TransferSummary {
+    TransferSummary {
+        direction: Direction::Download,
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        path: path.display().to_string(),
+        bytes: 0,
+        error: Some(format!("{:#}", error)),
+    }
+}
+
+fn pinned(e_tag: &str) -> Option<String> {
+    (!e_tag.is_empty()).then(|| e_tag.to_string())
+}
+
+async fn remove_if_exists(path: &Path) {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            warn!("Failed to remove {}: {}", path.display(), e)
+        }
+        _ => {}
+    }
+}
+
+/// Sidecar file a download is written to before being renamed into place.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_local_path_keeps_nested_keys_inside_dir() {
+        let path = safe_local_path(Path::new("out"), "logs/2024/app.log").unwrap();
+        assert_eq!(
+            path,
+            Path::new("out").join("logs").join("2024").join("app.log")
+        );
+    }
+
+    #[test]
+    fn safe_local_path_rejects_traversal() {
+        for key in [
+            "..",
+            "../etc/passwd",
+            "logs/../../etc/passwd",
+            ".",
+            "logs/./app.log",
+        ] {
+            assert!(safe_local_path(Path::new("out"), key).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn safe_local_path_rejects_empty_segments() {
+        for key in ["", "/etc/passwd", "logs//app.log", "logs/"] {
+            assert!(safe_local_path(Path::new("out"), key).is_err(), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn safe_local_path_rejects_invalid_separators() {
+        for key in ["..\\secret", "logs\\app.log", "app\0.log"] {
+            assert!(safe_local_path(Path::new("out"), key).is_err(), "{:?}", key);
+        }
+    }
+}
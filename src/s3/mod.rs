@@ -1 +1,9 @@
+mod checkpoint;
 pub mod client;
+mod copy;
+mod delete;
+mod download;
+pub mod sync;
+mod upload;
+#[cfg(target_os = "linux")]
+mod watch;
@@ -0,0 +1,1010 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, BoxStream};
+use futures::{future, Stream, StreamExt, TryStreamExt};
+use log::{info, warn};
+use md5::{Digest, Md5};
+
+use super::checkpoint::{is_checkpoint, FileIdentity, SyncState, SyncedFile};
+use super::client::{CopyOptions, Direction, ListEntry, S3Client, SyncOptions, TransferSummary};
+use super::download::part_path;
+use super::upload::{dir_prefix, effective_part_size, part_ranges, relative_key};
+use crate::utils::walk_files;
+
+/// The size and modification time sync compares, for a local file or an
+/// object, keyed by its path relative to the sync root.
+#[derive(Clone, Debug)]
+struct SyncEntry {
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+    /// For local files, only computed when comparing checksums or taken
+    /// from the sync state.
+    e_tag: Option<String>,
+    /// Only for local files.
+    identity: Option<FileIdentity>,
+}
+
+/// The other side of a sync from the S3 prefix a plan is anchored on.
+enum Peer {
+    /// The local directory uploaded from or downloaded to.
+    Dir(PathBuf),
+    /// The source of a bucket-to-bucket sync. Without a client of its own it
+    /// is on the destination's endpoint and objects are copied server-side.
+    Bucket {
+        client: Option<S3Client>,
+        bucket: String,
+        prefix: String,
+    },
+}
+
+/// The transfers and deletions that bring a destination in line with its
+/// source, as computed by [`S3Client::plan_sync`] or
+/// [`S3Client::plan_bucket_sync`].
+pub struct SyncPlan {
+    /// `Upload`, `Download`, or `Copy` between buckets.
+    direction: Direction,
+    /// The S3 side; the destination unless downloading.
+    bucket: String,
+    prefix: String,
+    peer: Peer,
+    /// Relative keys to transfer with their source sizes.
+    transfers: Vec<(String, u64)>,
+    /// Relative keys present only at the destination.
+    deletes: Vec<String>,
+    unchanged: usize,
+    /// Set when syncing with `options.state`.
+    state: Option<PlanState>,
+}
+
+/// The sync state a plan saves once it has been carried out.
+struct PlanState {
+    dir: PathBuf,
+    /// Starts out with the files the plan leaves alone.
+    state: SyncState,
+    /// The source entry of every planned transfer, recorded once it succeeds.
+    pending: BTreeMap<String, SyncEntry>,
+}
+
+impl SyncPlan {
+    /// The S3 prefix the plan is anchored on.
+    pub(super) fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// One summary per planned action, for printing a dry run.
+    pub fn summaries(&self) -> Vec<TransferSummary> {
+        let transfers = self
+            .transfers
+            .iter()
+            .map(|(relative, size)| self.summary(self.direction, relative, *size));
+        let deletes = self
+            .deletes
+            .iter()
+            .map(|relative| self.summary(Direction::Delete, relative, 0));
+        transfers.chain(deletes).collect()
+    }
+
+    fn summary(&self, direction: Direction, relative: &str, bytes: u64) -> TransferSummary {
+        let deleting_remote =
+            direction == Direction::Delete && self.direction != Direction::Download;
+        // Deleting on the S3 side has no local file or source object to name.
+        let path = match &self.peer {
+            _ if deleting_remote => String::new(),
+            Peer::Dir(dir) => dir.join(relative).display().to_string(),
+            Peer::Bucket { bucket, prefix, .. } => {
+                format!("s3://{}/{}{}", bucket, prefix, relative)
+            }
+        };
+        TransferSummary {
+            direction,
+            bucket: self.bucket.clone(),
+            key: format!("{}{}", self.prefix, relative),
+            path,
+            bytes,
+            error: None,
+        }
+    }
+
+    /// Plans a transfer for every source entry the destination lacks or holds
+    /// a different version of, and with `options.delete` a deletion for every
+    /// destination entry without a source. `trusted` entries are known to be
+    /// in sync and need no destination entry.
+    fn compare(
+        &mut self,
+        source: &BTreeMap<String, SyncEntry>,
+        destination: &BTreeMap<String, SyncEntry>,
+        trusted: &BTreeSet<String>,
+        options: &SyncOptions,
+    ) {
+        for (relative, src) in source {
+            let changed = match destination.get(relative) {
+                _ if trusted.contains(relative) => false,
+                None => true,
+                Some(dst) if src.size != dst.size => true,
+                Some(dst) if options.checksum => src.e_tag.is_none() || src.e_tag != dst.e_tag,
+                // S3 timestamps only have whole seconds.
+                Some(dst) => {
+                    src.modified.map(|d| d.timestamp()) > dst.modified.map(|d| d.timestamp())
+                }
+            };
+            if changed {
+                self.transfers.push((relative.clone(), src.size));
+            } else {
+                self.unchanged += 1;
+            }
+        }
+        if options.delete {
+            self.deletes = destination
+                .keys()
+                .filter(|relative| !source.contains_key(*relative))
+                .cloned()
+                .collect();
+        }
+        info!(
+            "Sync plan: {} to transfer, {} to delete, {} unchanged",
+            self.transfers.len(),
+            self.deletes.len(),
+            self.unchanged
+        );
+    }
+}
+
+impl S3Client {
+    /// Compares `dir` with the objects under `prefix` and plans the work that
+    /// makes the destination side match the source side.
+    ///
+    /// `Direction::Upload` syncs `dir` to S3 and `Direction::Download` the
+    /// reverse. A file is transferred when the destination lacks it, its size
+    /// differs, or the source was modified after the destination. With
+    /// `options.checksum`, the local file's MD5 is compared with the ETag
+    /// instead of the timestamps. With `options.delete`, destination entries
+    /// that have no source are planned for deletion.
+    ///
+    /// With `options.state`, local files still matching the size, modification
+    /// time and inode recorded by the last sync reuse its ETag. An upload
+    /// trusts them to be in sync and only lists the prefixes holding new,
+    /// changed or removed files, so objects altered behind its back go
+    /// unnoticed until `options.rebuild_state` forces a full comparison.
+    pub async fn plan_sync(
+        &self,
+        direction: Direction,
+        dir: &Path,
+        bucket: &str,
+        prefix: &str,
+        options: &SyncOptions,
+    ) -> Result<SyncPlan> {
+        let prefix = dir_prefix(prefix);
+        let uploading = direction == Direction::Upload;
+        let mut local = scan_local(dir, !uploading)?;
+        let previous = options
+            .state
+            .then(|| load_state(dir, bucket, &prefix, options.rebuild_state))
+            .flatten();
+
+        let mut trusted = BTreeSet::new();
+        let remote = match &previous {
+            Some(previous) => {
+                for (relative, file) in local.iter_mut() {
+                    let Some(synced) = previous.files.get(relative) else {
+                        continue;
+                    };
+                    if file.identity == Some(synced.local) {
+                        file.e_tag = synced.e_tag.clone();
+                        trusted.insert(relative.clone());
+                    }
+                }
+                if uploading {
+                    let dirs = local
+                        .keys()
+                        .filter(|relative| !trusted.contains(*relative))
+                        .chain(previous.files.keys().filter(|r| !local.contains_key(*r)))
+                        .map(|relative| parent_dir(relative))
+                        .collect();
+                    info!(
+                        "{} files changed since the last sync",
+                        local.len() - trusted.len()
+                    );
+                    self.scan_dirs(bucket, &prefix, dirs, options.transfer.concurrency)
+                        .await?
+                } else {
+                    // The objects may have changed even if the files have not.
+                    trusted.clear();
+                    self.scan_remote(bucket, &prefix).await?
+                }
+            }
+            None => self.scan_remote(bucket, &prefix).await?,
+        };
+        if options.checksum {
+            fill_e_tags(dir, &mut local, &remote, options).await?;
+        }
+
+        let mut plan = SyncPlan {
+            direction,
+            bucket: bucket.to_string(),
+            prefix: prefix.clone(),
+            peer: Peer::Dir(dir.to_path_buf()),
+            transfers: Vec::new(),
+            deletes: Vec::new(),
+            unchanged: 0,
+            state: None,
+        };
+        if uploading {
+            plan.compare(&local, &remote, &trusted, options);
+        } else {
+            plan.compare(&remote, &local, &trusted, options);
+        }
+        if options.state {
+            let source = if uploading { &local } else { &remote };
+            plan.state = Some(plan_state(&plan, dir, source, &local, &remote));
+        }
+        Ok(plan)
+    }
+
+    /// Plans the upload of the `changed` files under `dir` and, with
+    /// `options.delete`, the deletion of the objects of `removed` ones,
+    /// without listing anything. Changed files that no longer exist count as
+    /// removed. Watch mode learns about these from the file system.
+    pub(super) fn plan_changes(
+        &self,
+        dir: &Path,
+        bucket: &str,
+        prefix: &str,
+        changed: Vec<String>,
+        mut removed: Vec<String>,
+        options: &SyncOptions,
+    ) -> SyncPlan {
+        let prefix = dir_prefix(prefix);
+        let mut local = BTreeMap::new();
+        for relative in changed {
+            match fs::metadata(dir.join(&relative)) {
+                Ok(metadata) if metadata.is_file() => {
+                    let file = SyncEntry {
+                        size: metadata.len(),
+                        modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+                        e_tag: None,
+                        identity: FileIdentity::from_metadata(&metadata).ok(),
+                    };
+                    local.insert(relative, file);
+                }
+                _ => removed.push(relative),
+            }
+        }
+
+        let mut plan = SyncPlan {
+            direction: Direction::Upload,
+            bucket: bucket.to_string(),
+            prefix: prefix.clone(),
+            peer: Peer::Dir(dir.to_path_buf()),
+            transfers: local
+                .iter()
+                .map(|(relative, file)| (relative.clone(), file.size))
+                .collect(),
+            deletes: if options.delete {
+                removed.clone()
+            } else {
+                Vec::new()
+            },
+            unchanged: 0,
+            state: None,
+        };
+        if options.state {
+            let mut state = load_state(dir, bucket, &prefix, false).unwrap_or(SyncState {
+                bucket: bucket.to_string(),
+                prefix,
+                files: BTreeMap::new(),
+            });
+            for relative in local.keys().chain(&removed) {
+                state.files.remove(relative);
+            }
+            plan.state = Some(PlanState {
+                dir: dir.to_path_buf(),
+                state,
+                pending: local,
+            });
+        }
+        plan
+    }
+
+    /// Plans a sync from `src_prefix` in `src_bucket` to `dst_prefix` in
+    /// `dst_bucket` on this client's endpoint, comparing objects like
+    /// [`S3Client::plan_sync`]; `options.checksum` compares the two ETags.
+    ///
+    /// `source` is the client for the source bucket's endpoint. `None` means
+    /// both buckets are reachable through this client, so objects are copied
+    /// server-side; otherwise they are streamed from one endpoint to the other.
+    pub async fn plan_bucket_sync(
+        &self,
+        source: Option<&S3Client>,
+        src_bucket: &str,
+        src_prefix: &str,
+        dst_bucket: &str,
+        dst_prefix: &str,
+        options: &SyncOptions,
+    ) -> Result<SyncPlan> {
+        let (src_prefix, dst_prefix) = (dir_prefix(src_prefix), dir_prefix(dst_prefix));
+        let objects = source
+            .unwrap_or(self)
+            .scan_remote(src_bucket, &src_prefix)
+            .await?;
+        let existing = self.scan_remote(dst_bucket, &dst_prefix).await?;
+
+        let mut plan = SyncPlan {
+            direction: Direction::Copy,
+            bucket: dst_bucket.to_string(),
+            prefix: dst_prefix,
+            peer: Peer::Bucket {
+                client: source.cloned(),
+                bucket: src_bucket.to_string(),
+                prefix: src_prefix,
+            },
+            transfers: Vec::new(),
+            deletes: Vec::new(),
+            unchanged: 0,
+            state: None,
+        };
+        plan.compare(&objects, &existing, &BTreeSet::new(), options);
+        Ok(plan)
+    }
+
+    /// Carries out a plan through the recursive upload, download or copy
+    /// paths, then performs its deletions once every transfer has finished.
+    /// Copies between buckets keep the source's metadata, content headers and
+    /// tags. A plan made with `options.state` saves the new sync state last.
+    pub fn run_sync(
+        &self,
+        mut plan: SyncPlan,
+        options: &SyncOptions,
+    ) -> BoxStream<'static, TransferSummary> {
+        let state = plan.state.take();
+        let transfers = match (&plan.peer, plan.direction) {
+            (Peer::Dir(dir), Direction::Upload) => {
+                let files = plan
+                    .transfers
+                    .iter()
+                    .map(|(relative, _)| (dir.join(relative), PathBuf::from(relative)))
+                    .collect();
+                self.upload_files(&plan.bucket, &plan.prefix, files, &options.transfer)
+                    .boxed()
+            }
+            (Peer::Dir(dir), _) => {
+                let keys = plan
+                    .transfers
+                    .iter()
+                    .map(|(relative, _)| Ok(format!("{}{}", plan.prefix, relative)))
+                    .collect::<Vec<_>>();
+                self.download_keys(
+                    &plan.bucket,
+                    &plan.prefix,
+                    dir,
+                    stream::iter(keys),
+                    &options.transfer,
+                )
+                .boxed()
+            }
+            (Peer::Bucket { .. }, _) => self.sync_copies(&plan, options).boxed(),
+        };
+        let client = self.clone();
+        let deletes =
+            stream::once(async move { client.sync_deletes(&plan).await }).flat_map(stream::iter);
+        let results = transfers.chain(deletes);
+        match state {
+            Some(state) => self
+                .record_state(results, state, options.transfer.concurrency)
+                .boxed(),
+            None => results.boxed(),
+        }
+    }
+
+    /// Passes `results` through, then saves `state` with every successful
+    /// transfer added. Failing to save only costs the next sync a full
+    /// comparison, so it is logged rather than reported as a failed transfer.
+    fn record_state(
+        &self,
+        results: impl Stream<Item = TransferSummary>,
+        state: PlanState,
+        concurrency: usize,
+    ) -> impl Stream<Item = TransferSummary> {
+        let transferred = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&transferred);
+        let client = self.clone();
+        let save = async move {
+            let keys = std::mem::take(&mut *transferred.lock().unwrap());
+            if let Err(e) = client.save_state(state, keys, concurrency).await {
+                warn!("Failed to save the sync state: {:#}", e);
+            }
+        };
+        results
+            .inspect(move |summary| {
+                if summary.error.is_none() && summary.direction != Direction::Delete {
+                    recorded.lock().unwrap().push(summary.key.clone());
+                }
+            })
+            .chain(stream::once(save).filter_map(|()| future::ready(None)))
+    }
+
+    /// Adds the transferred `keys` to the state and writes it out. Uploaded
+    /// files are recorded as scanned, with the ETags of their new objects;
+    /// downloaded ones as written, with the ETags they were downloaded at.
+    async fn save_state(
+        &self,
+        plan: PlanState,
+        keys: Vec<String>,
+        concurrency: usize,
+    ) -> Result<()> {
+        let PlanState {
+            dir,
+            mut state,
+            mut pending,
+        } = plan;
+        let mut uploaded = BTreeMap::new();
+        for key in keys {
+            let Some(relative) = key.strip_prefix(&state.prefix) else {
+                continue;
+            };
+            let Some(source) = pending.remove(relative) else {
+                continue;
+            };
+            if let Some(identity) = source.identity {
+                uploaded.insert(relative.to_string(), identity);
+                continue;
+            }
+            match fs::metadata(dir.join(relative)).map(|m| FileIdentity::from_metadata(&m)) {
+                Ok(Ok(identity)) => {
+                    let file = SyncedFile {
+                        local: identity,
+                        e_tag: source.e_tag,
+                    };
+                    state.files.insert(relative.to_string(), file);
+                }
+                _ => warn!("Not recording {} in the sync state", relative),
+            }
+        }
+        if !uploaded.is_empty() {
+            let dirs = uploaded
+                .keys()
+                .map(|relative| parent_dir(relative))
+                .collect();
+            let objects = self
+                .scan_dirs(&state.bucket, &state.prefix, dirs, concurrency)
+                .await?;
+            for (relative, identity) in uploaded {
+                let e_tag = objects
+                    .get(&relative)
+                    .filter(|object| object.size == identity.fingerprint.size)
+                    .and_then(|object| object.e_tag.clone());
+                let file = SyncedFile {
+                    local: identity,
+                    e_tag,
+                };
+                state.files.insert(relative, file);
+            }
+        }
+        fs::create_dir_all(&dir)?;
+        state.save(&SyncState::path_for(&dir))?;
+        info!("Saved the sync state of {} files", state.files.len());
+        Ok(())
+    }
+
+    fn sync_copies(
+        &self,
+        plan: &SyncPlan,
+        options: &SyncOptions,
+    ) -> impl Stream<Item = TransferSummary> {
+        let Peer::Bucket {
+            client: source,
+            bucket: src_bucket,
+            prefix: src_prefix,
+        } = &plan.peer
+        else {
+            unreachable!("sync_copies is only called for bucket-to-bucket plans");
+        };
+        let options = CopyOptions {
+            transfer: options.transfer,
+            tags: true,
+        };
+        let copies: Vec<_> = plan
+            .transfers
+            .iter()
+            .map(|(relative, _)| {
+                (
+                    format!("{}{}", src_prefix, relative),
+                    format!("{}{}", plan.prefix, relative),
+                    plan.summary(Direction::Copy, relative, 0),
+                )
+            })
+            .collect();
+        let (client, source) = (self.clone(), source.clone());
+        let (src_bucket, dst_bucket) = (src_bucket.clone(), plan.bucket.clone());
+        // Tasks are spawned as buffer_unordered asks for them, so only
+        // `concurrency` copies run at once however many are planned.
+        let jobs = stream::iter(copies).map(move |(src_key, dst_key, mut failed)| {
+            let (client, source) = (client.clone(), source.clone());
+            let (src_bucket, dst_bucket) = (src_bucket.clone(), dst_bucket.clone());
+            tokio::spawn(async move {
+                let result = match &source {
+                    Some(source) => {
+                        client
+                            .copy_object_from(
+                                source,
+                                &src_bucket,
+                                &src_key,
+                                &dst_bucket,
+                                &dst_key,
+                                &options,
+                            )
+                            .await
+                    }
+                    None => {
+                        client
+                            .copy_object(&src_bucket, &src_key, &dst_bucket, &dst_key, &options)
+                            .await
+                    }
+                };
+                result.unwrap_or_else(|e| {
+                    failed.error = Some(format!("{:#}", e));
+                    failed
+                })
+            })
+        });
+        jobs.buffer_unordered(options.transfer.concurrency)
+            .map(|joined| {
+                joined.unwrap_or_else(|e| TransferSummary {
+                    direction: Direction::Copy,
+                    bucket: String::new(),
+                    key: String::new(),
+                    path: String::new(),
+                    bytes: 0,
+                    error: Some(e.to_string()),
+                })
+            })
+    }
+
+    async fn sync_deletes(&self, plan: &SyncPlan) -> Vec<TransferSummary> {
+        if let (Peer::Dir(dir), Direction::Download) = (&plan.peer, plan.direction) {
+            let mut results = Vec::with_capacity(plan.deletes.len());
+            for relative in &plan.deletes {
+                let mut summary = plan.summary(Direction::Delete, relative, 0);
+                if let Err(e) = tokio::fs::remove_file(dir.join(relative)).await {
+                    summary.error = Some(e.to_string());
+                }
+                results.push(summary);
+            }
+            return results;
+        }
+        let keys = plan
+            .deletes
+            .iter()
+            .map(|relative| (format!("{}{}", plan.prefix, relative), None))
+            .collect();
+        self.delete_batch(&plan.bucket, keys)
+            .await
+            .into_iter()
+            .map(|deleted| TransferSummary {
+                direction: Direction::Delete,
+                bucket: deleted.bucket,
+                key: deleted.key,
+                path: String::new(),
+                bytes: 0,
+                error: deleted.error,
+            })
+            .collect()
+    }
+
+    /// Lists the objects under `prefix` keyed by their path below it.
+    async fn scan_remote(&self, bucket: &str, prefix: &str) -> Result<BTreeMap<String, SyncEntry>> {
+        let entries = self.list_objects(bucket, Some(prefix), None);
+        objects_below(entries, prefix).await
+    }
+
+    /// Lists only the objects directly in each of `dirs`, which are relative
+    /// to `prefix`, keyed by their path below `prefix`.
+    async fn scan_dirs(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        dirs: BTreeSet<String>,
+        concurrency: usize,
+    ) -> Result<BTreeMap<String, SyncEntry>> {
+        info!("Listing {} prefixes", dirs.len());
+        stream::iter(dirs)
+            .map(|dir| {
+                let entries =
+                    self.list_objects(bucket, Some(&format!("{}{}", prefix, dir)), Some("/"));
+                objects_below(entries, prefix)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .try_fold(BTreeMap::new(), |mut objects, listed| async move {
+                objects.extend(listed);
+                Ok(objects)
+            })
+            .await
+    }
+}
+
+/// The sync state to save for `plan`: every `source` entry left alone is
+/// recorded right away, the transferred ones once they succeed.
+fn plan_state(
+    plan: &SyncPlan,
+    dir: &Path,
+    source: &BTreeMap<String, SyncEntry>,
+    local: &BTreeMap<String, SyncEntry>,
+    remote: &BTreeMap<String, SyncEntry>,
+) -> PlanState {
+    let transferring: BTreeSet<&str> = plan.transfers.iter().map(|(r, _)| r.as_str()).collect();
+    let mut files = BTreeMap::new();
+    let mut pending = BTreeMap::new();
+    for (relative, entry) in source {
+        if transferring.contains(relative.as_str()) {
+            pending.insert(relative.clone(), entry.clone());
+            continue;
+        }
+        let Some(file) = local.get(relative) else {
+            continue;
+        };
+        let Some(identity) = file.identity else {
+            continue;
+        };
+        // Trusted files were not listed; their ETag comes from the old state.
+        let e_tag = remote
+            .get(relative)
+            .and_then(|object| object.e_tag.clone())
+            .or_else(|| file.e_tag.clone());
+        files.insert(
+            relative.clone(),
+            SyncedFile {
+                local: identity,
+                e_tag,
+            },
+        );
+    }
+    PlanState {
+        dir: dir.to_path_buf(),
+        state: SyncState {
+            bucket: plan.bucket.clone(),
+            prefix: plan.prefix.clone(),
+            files,
+        },
+        pending,
+    }
+}
+
+/// Loads the sync state of `dir` unless it is being rebuilt or was saved for
+/// a different bucket or prefix.
+fn load_state(dir: &Path, bucket: &str, prefix: &str, rebuild: bool) -> Option<SyncState> {
+    if rebuild {
+        info!("Rebuilding the sync state of {}", dir.display());
+        return None;
+    }
+    let state = SyncState::load(&SyncState::path_for(dir))?;
+    if state.bucket != bucket || state.prefix != prefix {
+        warn!(
+            "Ignoring the sync state in {}, which was saved for s3://{}/{}",
+            dir.display(),
+            state.bucket,
+            state.prefix
+        );
+        return None;
+    }
+    Some(state)
+}
+
+/// The `/`-terminated directory part of a relative path, empty at the top.
+fn parent_dir(relative: &str) -> String {
+    relative
+        .rfind('/')
+        .map(|slash| relative[..=slash].to_string())
+        .unwrap_or_default()
+}
+
+async fn objects_below(
+    entries: impl Stream<Item = Result<ListEntry>>,
+    prefix: &str,
+) -> Result<BTreeMap<String, SyncEntry>> {
+    entries
+        .try_fold(BTreeMap::new(), |mut objects, entry| async move {
+            if let ListEntry::Object(object) = entry {
+                let relative = object.key.strip_prefix(prefix).unwrap_or(&object.key);
+                // Zero-byte "directory" placeholders are not files.
+                if !relative.is_empty() && !relative.ends_with('/') {
+                    objects.insert(
+                        relative.to_string(),
+                        SyncEntry {
+                            size: object.size,
+                            modified: object.last_modified,
+                            e_tag: object.e_tag.map(|e| e.trim_matches('"').to_string()),
+                            identity: None,
+                        },
+                    );
+                }
+            }
+            Ok(objects)
+        })
+        .await
+}
+
+/// Lists the files under `dir` keyed by their `/`-separated relative path.
+///
+/// rs3's checkpoint files are never synced. When `downloading`, neither are
+/// the `.part` files of downloads in progress, and a missing `dir` is treated
+/// as empty so the download can create it; as an upload source it is an error.
+fn scan_local(dir: &Path, downloading: bool) -> Result<BTreeMap<String, SyncEntry>> {
+    if downloading && !dir.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let files = walk_files(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    let mut entries = BTreeMap::new();
+    for path in files {
+        if is_checkpoint(&path) || (downloading && is_part_file(&path)) {
+            continue;
+        }
+        let Some(relative) = relative_key(path.strip_prefix(dir).unwrap_or(&path)) else {
+            warn!("Skipping {}: file name is not valid UTF-8", path.display());
+            continue;
+        };
+        let metadata = path.metadata()?;
+        entries.insert(
+            relative,
+            SyncEntry {
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+                e_tag: None,
+                identity: FileIdentity::from_metadata(&metadata).ok(),
+            },
+        );
+    }
+    Ok(entries)
+}
+
+/// Whether `path` is the `.part` file of an interrupted download.
+fn is_part_file(path: &Path) -> bool {
+    let Some(name) = path.to_str().and_then(|p| p.strip_suffix(".part")) else {
+        return false;
+    };
+    part_path(Path::new(name)) == path
+}
+
+/// Computes the ETag of every local file whose object has the same size, in
+/// the object's form: a plain ETag is the MD5 of the object, a multipart one
+/// (`md5-N`) the MD5 of the part MD5s. The latter only matches when the file
+/// is split like the upload was, so rs3's own part size is assumed; files that
+/// cannot be compared (e.g. SSE-KMS objects) end up counting as changed.
+/// ETags already taken from the sync state are kept.
+async fn fill_e_tags(
+    dir: &Path,
+    local: &mut BTreeMap<String, SyncEntry>,
+    remote: &BTreeMap<String, SyncEntry>,
+    options: &SyncOptions,
+) -> Result<()> {
+    for (relative, file) in local.iter_mut().filter(|(_, file)| file.e_tag.is_none()) {
+        let Some(e_tag) = remote
+            .get(relative)
+            .filter(|object| object.size == file.size)
+            .and_then(|object| object.e_tag.as_deref())
+        else {
+            continue;
+        };
+        let part_size = e_tag
+            .contains('-')
+            .then(|| effective_part_size(file.size, options.transfer.part_size));
+        let (path, size) = (dir.join(relative), file.size);
+        let computed =
+            tokio::task::spawn_blocking(move || file_e_tag(&path, size, part_size)).await??;
+        file.e_tag = Some(computed);
+    }
+    Ok(())
+}
+
+/// Computes the ETag S3 would report for `path`: the MD5 of the whole file,
+/// or with `part_size` the multipart form over parts of that size.
+fn file_e_tag(path: &Path, size: u64, part_size: Option<u64>) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut digest_part = |length: u64| -> Result<_> {
+        let mut md5 = Md5::new();
+        let mut left = length;
+        while left > 0 {
+            let want = left.min(buffer.len() as u64) as usize;
+            let read = file.read(&mut buffer[..want])?;
+            if read == 0 {
+                bail!("{} changed while computing its checksum", path.display());
+            }
+            md5.update(&buffer[..read]);
+            left -= read as u64;
+        }
+        Ok(md5.finalize())
+    };
+    let Some(part_size) = part_size else {
+        return Ok(hex(&digest_part(size)?));
+    };
+    let mut combined = Md5::new();
+    let mut parts = 0;
+    for (_, _, length) in part_ranges(size, part_size.max(1)) {
+        combined.update(digest_part(length)?);
+        parts += 1;
+    }
+    Ok(format!("{}-{}", hex(&combined.finalize()), parts))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::client::TransferOptions;
+    use chrono::TimeZone;
+
+    fn options(delete: bool, checksum: bool) -> SyncOptions {
+        SyncOptions {
+            transfer: TransferOptions {
+                multipart_threshold: 8 * 1024 * 1024,
+                part_size: 8 * 1024 * 1024,
+                concurrency: 1,
+                resumable: false,
+            },
+            delete,
+            checksum,
+            state: false,
+            rebuild_state: false,
+        }
+    }
+
+    fn plan() -> SyncPlan {
+        SyncPlan {
+            direction: Direction::Upload,
+            bucket: "bucket".to_string(),
+            prefix: String::new(),
+            peer: Peer::Dir(PathBuf::from("dir")),
+            transfers: Vec::new(),
+            deletes: Vec::new(),
+            unchanged: 0,
+            state: None,
+        }
+    }
+
+    fn entry(size: u64, millis: i64, e_tag: Option<&str>) -> SyncEntry {
+        SyncEntry {
+            size,
+            modified: Utc.timestamp_millis_opt(millis).single(),
+            e_tag: e_tag.map(String::from),
+            identity: None,
+        }
+    }
+
+    fn entries(list: &[(&str, SyncEntry)]) -> BTreeMap<String, SyncEntry> {
+        list.iter()
+            .map(|(relative, entry)| (relative.to_string(), entry.clone()))
+            .collect()
+    }
+
+    fn compare(
+        source: &[(&str, SyncEntry)],
+        destination: &[(&str, SyncEntry)],
+        options: &SyncOptions,
+    ) -> SyncPlan {
+        let mut plan = plan();
+        plan.compare(
+            &entries(source),
+            &entries(destination),
+            &BTreeSet::new(),
+            options,
+        );
+        plan
+    }
+
+    fn transferred(plan: &SyncPlan) -> Vec<&str> {
+        plan.transfers
+            .iter()
+            .map(|(relative, _)| relative.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn compare_skips_unchanged_and_older_sources() {
+        let plan = compare(
+            &[
+                ("same", entry(3, 5_000, None)),
+                ("older", entry(3, 1_000, None)),
+            ],
+            &[
+                ("same", entry(3, 5_000, None)),
+                ("older", entry(3, 2_000, None)),
+            ],
+            &options(false, false),
+        );
+        assert!(plan.transfers.is_empty());
+        assert_eq!(plan.unchanged, 2);
+    }
+
+    #[test]
+    fn compare_transfers_new_newer_and_resized_sources() {
+        let plan = compare(
+            &[
+                ("new", entry(1, 0, None)),
+                ("newer", entry(3, 9_000, None)),
+                ("resized", entry(4, 1_000, None)),
+            ],
+            &[
+                ("newer", entry(3, 5_000, None)),
+                ("resized", entry(3, 5_000, None)),
+            ],
+            &options(false, false),
+        );
+        assert_eq!(transferred(&plan), ["new", "newer", "resized"]);
+        assert_eq!(plan.transfers[2], ("resized".to_string(), 4));
+    }
+
+    #[test]
+    fn compare_truncates_to_whole_seconds() {
+        // S3 drops the milliseconds of a just-uploaded file.
+        let plan = compare(
+            &[("file", entry(3, 5_999, None))],
+            &[("file", entry(3, 5_000, None))],
+            &options(false, false),
+        );
+        assert!(plan.transfers.is_empty());
+    }
+
+    #[test]
+    fn compare_uses_checksums_instead_of_times() {
+        let plan = compare(
+            &[
+                ("match", entry(3, 9_000, Some("abc"))),
+                ("mismatch", entry(3, 1_000, Some("abc"))),
+                ("unknown", entry(3, 1_000, None)),
+            ],
+            &[
+                ("match", entry(3, 1_000, Some("abc"))),
+                ("mismatch", entry(3, 9_000, Some("def"))),
+                ("unknown", entry(3, 9_000, Some("abc"))),
+            ],
+            &options(false, true),
+        );
+        assert_eq!(transferred(&plan), ["mismatch", "unknown"]);
+        assert_eq!(plan.unchanged, 1);
+    }
+
+    #[test]
+    fn compare_deletes_only_with_delete() {
+        let source = [("kept", entry(1, 0, None))];
+        let destination = [("kept", entry(1, 0, None)), ("extra", entry(1, 0, None))];
+        let plan = compare(&source, &destination, &options(false, false));
+        assert!(plan.deletes.is_empty());
+        let plan = compare(&source, &destination, &options(true, false));
+        assert_eq!(plan.deletes, ["extra"]);
+    }
+
+    #[test]
+    fn compare_trusts_files_from_the_state() {
+        let mut plan = plan();
+        let trusted = BTreeSet::from(["file".to_string()]);
+        plan.compare(
+            &entries(&[("file", entry(3, 9_000, None))]),
+            &BTreeMap::new(),
+            &trusted,
+            &options(true, false),
+        );
+        assert!(plan.transfers.is_empty());
+        assert_eq!(plan.unchanged, 1);
+    }
+
+    #[test]
+    fn file_e_tag_matches_s3() {
+        let path = std::env::temp_dir().join(format!("rs3-e-tag-{}", std::process::id()));
+        fs::write(&path, b"0123456789").unwrap();
+        let whole = file_e_tag(&path, 10, None);
+        let multipart = file_e_tag(&path, 10, Some(4));
+        let shrunk = file_e_tag(&path, 11, None);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(whole.unwrap(), "781e5e245d69b566979b86e28d23f2c7");
+        assert_eq!(multipart.unwrap(), "61e3716e3a7767581863b67c4e785584-3");
+        assert!(shrunk.is_err());
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use log::{info, warn};
+
+use super::checkpoint::{is_checkpoint, CheckpointPart, FileFingerprint, UploadCheckpoint};
+use super::client::{Direction, S3Client, TransferOptions, TransferSummary, MAX_PUT_SIZE};
+use crate::utils::{format_size, run_concurrent, walk_files};
+
+/// S3 rejects parts smaller than this, except for the last one.
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// S3 allows at most this many parts in one multipart upload.
+pub const MAX_PARTS: u64 = 10_000;
+
+/// Whether an object of `bytes` is sent as a multipart upload: from
+/// `threshold` on, and always above what a single request can carry. Empty
+/// objects never are, since a multipart upload needs at least one part.
+pub fn needs_multipart(bytes: u64, threshold: u64) -> bool {
+    bytes > MAX_PUT_SIZE || (bytes > 0 && bytes >= threshold)
+}
+
+impl S3Client {
+    /// Uploads every file under `dir` to keys below `prefix`.
+    ///
+    /// Files are uploaded by at most `options.concurrency` tokio tasks and a
+    /// summary is yielded for each one as it finishes; failures are reported in
+    /// the summary's `error` rather than stopping the remaining uploads.
+    pub fn upload_dir(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        dir: &Path,
+        options: &TransferOptions,
+    ) -> Result<impl Stream<Item = TransferSummary>> {
+        let prefix = dir_prefix(prefix);
+        let files = walk_files(dir)?
+            .into_iter()
+            .filter(|path| !is_checkpoint(path))
+            .map(|path| {
+                let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+                (path, relative)
+            })
+            .collect::<Vec<_>>();
+        info!("Uploading {} files from {}", files.len(), dir.display());
+        Ok(self.upload_files(bucket, &prefix, files, options))
+    }
+
+    /// Uploads `(path, relative)` pairs to `prefix` joined with each relative
+    /// path, reporting one summary per file like [`S3Client::upload_dir`].
+    ///
+    /// Failed multipart uploads are always aborted: a whole tree is not
+    /// re-run for one file, so kept uploads would only pile up on the server.
+    pub(super) fn upload_files(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        files: Vec<(PathBuf, PathBuf)>,
+        options: &TransferOptions,
+    ) -> impl Stream<Item = TransferSummary> {
+        let (client, bucket, prefix, options) = (
+            self.clone(),
+            bucket.to_string(),
+            prefix.to_string(),
+            TransferOptions {
+                resumable: false,
+                ..*options
+            },
+        );
+        stream::iter(files)
+            .map(move |(path, relative)| {
+                let (client, bucket) = (client.clone(), bucket.clone());
+                let key = relative_key(&relative).map(|rel| format!("{}{}", prefix, rel));
+                tokio::spawn(async move {
+                    let file_path = path.display().to_string();
+                    let result = match &key {
+                        Some(key) => {
+                            client
+                                .upload_object(&bucket, key, &file_path, &options)
+                                .await
+                        }
+                        None => Err(anyhow::anyhow!("file name is not valid UTF-8")),
+                    };
+                    result.unwrap_or_else(|e| TransferSummary {
+                        direction: Direction::Upload,
+                        bucket,
+                        key: key.unwrap_or_default(),
+                        path: file_path,
+                        bytes: 0,
+                        error: Some(format!("{:#}", e)),
+                    })
+                })
+            })
+            .buffer_unordered(options.concurrency)
+            .map(|joined| {
+                joined.unwrap_or_else(|e| TransferSummary {
+                    direction: Direction::Upload,
+                    bucket: String::new(),
+                    key: String::new(),
+                    path: String::new(),
+                    bytes: 0,
+                    error: Some(e.to_string()),
+                })
+            })
+    }
+
+    /// Uploads `path` as a multipart upload of `size` bytes.
+    ///
+    /// Parts are read straight from disk and sent concurrently. When
+    /// `options.resumable` is set, the upload id and every completed part are
+    /// recorded in a checkpoint next to the file; a failed upload is left in
+    /// place and re-running the same command uploads only the missing parts.
+    /// Otherwise, or when the checkpoint cannot be written, e.g. next to a file
+    /// in a read-only directory, a failure aborts the upload so no orphaned
+    /// parts are left.
+    pub(super) async fn upload_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        size: u64,
+        options: &TransferOptions,
+    ) -> Result<()> {
+        let part_size = effective_part_size(size, options.part_size);
+        let source = FileFingerprint::of(path)?;
+        let checkpoint_path = UploadCheckpoint::path_for(path);
+
+        let resumed = if options.resumable {
+            self.resume_checkpoint(&checkpoint_path, bucket, key, part_size, source)
+                .await?
+        } else {
+            None
+        };
+        let checkpoint = match resumed {
+            Some(checkpoint) => checkpoint,
+            None => {
+                let upload_id = self
+                    .client
+                    .create_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await?
+                    .upload_id
+                    .context("CreateMultipartUpload returned no upload id")?;
+                UploadCheckpoint {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                    upload_id,
+                    part_size,
+                    source,
+                    parts: Vec::new(),
+                }
+            }
+        };
+        let mut resumable = options.resumable;
+        if resumable {
+            if let Err(e) = checkpoint.save(&checkpoint_path) {
+                warn!(
+                    "Cannot write {}, the upload will not be resumable: {:#}",
+                    checkpoint_path.display(),
+                    e
+                );
+                resumable = false;
+            }
+        }
+        let upload_id = checkpoint.upload_id.clone();
+        info!(
+            "Uploading {} in {} parts of {} ({} already uploaded)",
+            path.display(),
+            size.div_ceil(part_size),
+            format_size(part_size),
+            checkpoint.parts.len()
+        );
+
+        let checkpoint = Arc::new(Mutex::new(checkpoint));
+        let result = async {
+            let checkpoint_path = resumable.then_some(checkpoint_path.as_path());
+            self.upload_parts(path, size, &checkpoint, checkpoint_path, options)
+                .await?;
+            let parts = checkpoint
+                .lock()
+                .unwrap()
+                .parts
+                .iter()
+                .map(|p| {
+                    CompletedPart::builder()
+                        .part_number(p.part_number)
+                        .e_tag(&p.e_tag)
+                        .build()
+                })
+                .collect();
+            self.complete_multipart(bucket, key, &upload_id, parts)
+                .await
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                if resumable {
+                    UploadCheckpoint::remove(&checkpoint_path);
+                }
+                Ok(())
+            }
+            Err(e) if resumable => {
+                warn!(
+                    "Multipart upload {} for '{}' was interrupted; re-run the same command to resume",
+                    upload_id, key
+                );
+                Err(e)
+            }
+            Err(e) => {
+                self.abort_multipart(bucket, key, &upload_id).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Validates an existing checkpoint against the local file and the parts
+    /// S3 actually holds, returning it trimmed to the parts that can be kept.
+    async fn resume_checkpoint(
+        &self,
+        checkpoint_path: &Path,
+        bucket: &str,
+        key: &str,
+        part_size: u64,
+        source: FileFingerprint,
+    ) -> Result<Option<UploadCheckpoint>> {
+        let Some(mut checkpoint) = UploadCheckpoint::load(checkpoint_path) else {
+            return Ok(None);
+        };
+        if checkpoint.bucket != bucket || checkpoint.key != key {
+            warn!(
+                "Ignoring checkpoint for s3://{}/{}, which targets a different object",
+                checkpoint.bucket, checkpoint.key
+            );
+            return Ok(None);
+        }
+        if checkpoint.source != source || checkpoint.part_size != part_size {
+            warn!("Local file changed since the interrupted upload, starting over");
+            self.abort_multipart(bucket, key, &checkpoint.upload_id)
+                .await;
+            return Ok(None);
+        }
+
+        let mut uploaded = self
+            .client
+            .list_parts()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&checkpoint.upload_id)
+            .into_paginator()
+            .items()
+            .send();
+        let uploaded: Vec<_> = match futures::stream::poll_fn(|cx| uploaded.poll_next(cx))
+            .try_collect()
+            .await
+        {
+            Ok(parts) => parts,
+            Err(e) => {
+                warn!(
+                    "Cannot resume multipart upload {}, starting over: {}",
+                    checkpoint.upload_id, e
+                );
+                return Ok(None);
+            }
+        };
+
+        let expected: Vec<_> = part_ranges(source.size, part_size).collect();
+        let recorded: HashSet<_> = checkpoint
+            .parts
+            .iter()
+            .map(|p| (p.part_number, p.e_tag.as_str()))
+            .collect();
+        let parts = uploaded
+            .iter()
+            .filter_map(|part| {
+                let number = part.part_number()?;
+                let e_tag = part.e_tag()?;
+                let (_, _, length) = expected.get(usize::try_from(number - 1).ok()?)?;
+                let intact = part.size() == Some(*length as i64)
+                    && (recorded.is_empty() || recorded.contains(&(number, e_tag)));
+                intact.then(|| CheckpointPart {
+                    part_number: number,
+                    e_tag: e_tag.to_string(),
+                })
+            })
+            .collect();
+        checkpoint.parts = parts;
+        info!("Resuming multipart upload {}", checkpoint.upload_id);
+        Ok(Some(checkpoint))
+    }
+
+    /// Uploads every part missing from `checkpoint`, recording each one as it
+    /// completes and saving it to `checkpoint_path` if given. Failing to save
+    /// only costs the part a re-upload when resuming.
+    async fn upload_parts(
+        &self,
+        path: &Path,
+        size: u64,
+        checkpoint: &Arc<Mutex<UploadCheckpoint>>,
+        checkpoint_path: Option<&Path>,
+        options: &TransferOptions,
+    ) -> Result<()> {
+        let (bucket, key, upload_id, part_size, done) = {
+            let cp = checkpoint.lock().unwrap();
+            let done: HashSet<i32> = cp.parts.iter().map(|p| p.part_number).collect();
+            (
+                cp.bucket.clone(),
+                cp.key.clone(),
+                cp.upload_id.clone(),
+                cp.part_size,
+                done,
+            )
+        };
+        let checkpoint_path = checkpoint_path.map(Path::to_path_buf);
+
+        let jobs = part_ranges(size, part_size)
+            .filter(|(part_number, _, _)| !done.contains(part_number))
+            .map(|(part_number, offset, length)| {
+                let client = self.client.clone();
+                let (bucket, key, upload_id) = (bucket.clone(), key.clone(), upload_id.clone());
+                let (path, checkpoint_path) = (PathBuf::from(path), checkpoint_path.clone());
+                let checkpoint = Arc::clone(checkpoint);
+                async move {
+                    let body = ByteStream::read_from()
+                        .path(&path)
+                        .offset(offset)
+                        .length(Length::Exact(length))
+                        .build()
+                        .await?;
+                    let resp = client
+                        .upload_part()
+                        .bucket(bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .body(body)
+                        .send()
+                        .await
+                        .with_context(|| format!("Failed to upload part {}", part_number))?;
+                    let mut cp = checkpoint.lock().unwrap();
+                    cp.parts.push(CheckpointPart {
+                        part_number,
+                        e_tag: resp.e_tag.unwrap_or_default(),
+                    });
+                    if let Some(checkpoint_path) = &checkpoint_path {
+                        if let Err(e) = cp.save(checkpoint_path) {
+                            warn!("Failed to update {}: {:#}", checkpoint_path.display(), e);
+                        }
+                    }
+                    Ok(())
+                }
+            });
+        run_concurrent(options.concurrency, jobs).await?;
+        Ok(())
+    }
+
+    pub(super) async fn complete_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        mut parts: Vec<CompletedPart>,
+    ) -> Result<()> {
+        parts.sort_by_key(|p| p.part_number);
+        self.client
+            .complete_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Best-effort AbortMultipartUpload; failures are only logged.
+    pub(super) async fn abort_multipart(&self, bucket: &str, key: &str, upload_id: &str) {
+        warn!("Aborting multipart upload {} for '{}'", upload_id, key);
+        if let Err(e) = self
+            .client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            warn!("Failed to abort multipart upload {}: {}", upload_id, e);
+        }
+    }
+}
+
+/// Grows the requested part size when needed to respect S3's part limits.
+/// Ranged downloads use the same bounds.
+pub fn effective_part_size(size: u64, requested: u64) -> u64 {
+    let part_size = requested.max(MIN_PART_SIZE).max(size.div_ceil(MAX_PARTS));
+    if part_size != requested {
+        warn!(
+            "Part size {} is out of bounds for a {} object, using {}",
+            format_size(requested),
+            format_size(size),
+            format_size(part_size)
+        );
+    }
+    part_size
+}
+
+/// Splits `size` bytes into `(part_number, offset, length)` triples.
+pub fn part_ranges(size: u64, part_size: u64) -> impl Iterator<Item = (i32, u64, u64)> {
+    (0..size.div_ceil(part_size)).map(move |i| {
+        let offset = i * part_size;
+        (i as i32 + 1, offset, part_size.min(size - offset))
+    })
+}
+
+/// Normalizes a key prefix so relative paths can be appended to it.
+pub fn dir_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_start_matches('/');
+    if prefix.is_empty() || prefix.ends_with('/') {
+        prefix.to_string()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+/// Joins the components of a relative local path with `/`.
+pub fn relative_key(relative: &Path) -> Option<String> {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .map(|parts| parts.join("/"))
+}
@@ -0,0 +1,368 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use futures::stream::{self, Stream};
+use futures::StreamExt;
+use inotify::{EventMask, EventStream, Inotify, WatchDescriptor, WatchMask};
+use log::{info, warn};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time::{self, Instant};
+
+use super::checkpoint::is_checkpoint;
+use super::client::{Direction, S3Client, SyncOptions, TransferSummary};
+use super::sync::SyncPlan;
+use super::upload::relative_key;
+use crate::utils::walk_files;
+
+/// The first delay before retrying failed transfers, doubled after every
+/// consecutive failure up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+impl S3Client {
+    /// Keeps `prefix` in sync with `dir` until `dir` itself is removed.
+    ///
+    /// Starts with a full sync, then uploads files once no inotify event has
+    /// touched them for `settle`, so a file being written is uploaded when the
+    /// writer is done rather than after every write. With `options.delete`,
+    /// the objects of removed files are deleted. Failed transfers are retried
+    /// with exponential backoff; lost events or directories moved around make
+    /// it compare the whole tree again.
+    pub fn watch_sync(
+        &self,
+        dir: &Path,
+        bucket: &str,
+        prefix: &str,
+        options: &SyncOptions,
+        settle: Duration,
+    ) -> impl Stream<Item = Result<TransferSummary>> {
+        let (results, mut received) = mpsc::channel(64);
+        let (client, dir, bucket, prefix, options) = (
+            self.clone(),
+            dir.to_path_buf(),
+            bucket.to_string(),
+            prefix.to_string(),
+            *options,
+        );
+        tokio::spawn(async move {
+            let target = Target {
+                dir: &dir,
+                bucket: &bucket,
+                prefix: &prefix,
+            };
+            if let Err(e) = client.watch(target, options, settle, &results).await {
+                let _ = results.send(Err(e)).await;
+            }
+        });
+        stream::poll_fn(move |cx| received.poll_recv(cx))
+    }
+
+    async fn watch(
+        &self,
+        target: Target<'_>,
+        mut options: SyncOptions,
+        settle: Duration,
+        results: &Sender<Result<TransferSummary>>,
+    ) -> Result<()> {
+        let mut watcher = Watcher::new(target.dir)?;
+        info!("Watching {} for changes", target.dir.display());
+        let mut pending: BTreeMap<String, Pending> = BTreeMap::new();
+        let mut backoff = Backoff::default();
+        // Catch up with whatever changed while nobody was watching.
+        let mut rescan = true;
+        loop {
+            let now = Instant::now();
+            if rescan && backoff.ready(now) {
+                rescan = false;
+                pending.clear();
+                let failed = match self.plan_target(&target, &options).await {
+                    Ok(plan) => self.run_batch(plan, &options, results).await?,
+                    Err(e) => {
+                        warn!("Failed to compare {}: {:#}", target.dir.display(), e);
+                        rescan = true;
+                        backoff.failed();
+                        continue;
+                    }
+                };
+                // Later rescans should not discard the state rebuilt now.
+                options.rebuild_state = false;
+                backoff.finished(&failed);
+                requeue(&mut pending, failed);
+                continue;
+            }
+
+            let settled: Vec<String> = pending
+                .iter()
+                .filter(|(_, change)| change.since + settle <= now)
+                .map(|(relative, _)| relative.clone())
+                .collect();
+            if !rescan && !settled.is_empty() && backoff.ready(now) {
+                let (mut changed, mut removed) = (Vec::new(), Vec::new());
+                for relative in settled {
+                    match pending.remove(&relative) {
+                        Some(Pending { removed: true, .. }) => removed.push(relative),
+                        _ => changed.push(relative),
+                    }
+                }
+                let plan = self.plan_changes(
+                    target.dir,
+                    target.bucket,
+                    target.prefix,
+                    changed,
+                    removed,
+                    &options,
+                );
+                let failed = self.run_batch(plan, &options, results).await?;
+                backoff.finished(&failed);
+                requeue(&mut pending, failed);
+                continue;
+            }
+
+            let next = match pending.values().map(|change| change.since + settle).min() {
+                _ if rescan => backoff.until,
+                Some(due) => Some(backoff.until.map_or(due, |until| until.max(due))),
+                None => None,
+            };
+            tokio::select! {
+                changes = watcher.next() => {
+                    for change in changes? {
+                        let since = Instant::now();
+                        match change {
+                            Change::Modified(relative) => {
+                                pending.insert(relative, Pending { removed: false, since });
+                            }
+                            Change::Removed(relative) => {
+                                pending.insert(relative, Pending { removed: true, since });
+                            }
+                            Change::Rescan => {
+                                watcher = Watcher::new(target.dir)?;
+                                rescan = true;
+                            }
+                        }
+                    }
+                }
+                _ = time::sleep_until(next.unwrap_or(now)), if next.is_some() => {}
+            }
+        }
+    }
+
+    async fn plan_target(&self, target: &Target<'_>, options: &SyncOptions) -> Result<SyncPlan> {
+        self.plan_sync(
+            Direction::Upload,
+            target.dir,
+            target.bucket,
+            target.prefix,
+            options,
+        )
+        .await
+    }
+
+    /// Carries out `plan`, forwarding every summary, and returns the relative
+    /// paths whose transfer failed along with whether they were deletions.
+    async fn run_batch(
+        &self,
+        plan: SyncPlan,
+        options: &SyncOptions,
+        results: &Sender<Result<TransferSummary>>,
+    ) -> Result<Vec<(String, bool)>> {
+        let prefix = plan.prefix().to_string();
+        let mut summaries = self.run_sync(plan, options);
+        let mut failed = Vec::new();
+        while let Some(summary) = summaries.next().await {
+            if summary.error.is_some() {
+                if let Some(relative) = summary.key.strip_prefix(&prefix) {
+                    if !relative.is_empty() {
+                        let removed = summary.direction == Direction::Delete;
+                        failed.push((relative.to_string(), removed));
+                    }
+                }
+            }
+            if results.send(Ok(summary)).await.is_err() {
+                bail!("Stopped watching, nobody is reading the results");
+            }
+        }
+        Ok(failed)
+    }
+}
+
+/// The directory being watched and the prefix it is mirrored to.
+struct Target<'a> {
+    dir: &'a Path,
+    bucket: &'a str,
+    prefix: &'a str,
+}
+
+/// A file waiting for its events to settle.
+struct Pending {
+    removed: bool,
+    since: Instant,
+}
+
+/// Queues failed transfers again; they wait for the backoff to pass and for
+/// `settle`, counted from now.
+fn requeue(pending: &mut BTreeMap<String, Pending>, failed: Vec<(String, bool)>) {
+    let since = Instant::now();
+    for (relative, removed) in failed {
+        pending
+            .entry(relative)
+            .or_insert(Pending { removed, since });
+    }
+}
+
+/// Holds off retries while transfers keep failing, e.g. when the network or
+/// the endpoint is down.
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+impl Backoff {
+    fn ready(&self, now: Instant) -> bool {
+        self.until.is_none_or(|until| until <= now)
+    }
+
+    fn finished(&mut self, failed: &[(String, bool)]) {
+        if failed.is_empty() {
+            *self = Backoff::default();
+        } else {
+            self.failed();
+        }
+    }
+
+    fn failed(&mut self) {
+        let delay = MIN_BACKOFF
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_BACKOFF);
+        self.failures += 1;
+        self.until = Some(Instant::now() + delay);
+        warn!("Retrying failed transfers in {}s", delay.as_secs());
+    }
+}
+
+/// A change to the watched tree, by path relative to its root.
+enum Change {
+    /// A file was created, written to or moved in.
+    Modified(String),
+    /// A file was deleted or moved out.
+    Removed(String),
+    /// Events were lost or a directory was moved, so only comparing the whole
+    /// tree again is reliable.
+    Rescan,
+}
+
+/// Watches every directory of a tree with inotify, which on its own only
+/// watches single directories.
+struct Watcher {
+    root: PathBuf,
+    events: EventStream<Vec<u8>>,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Watcher {
+    fn new(root: &Path) -> Result<Self> {
+        let events = Inotify::init()?.into_event_stream(vec![0; 64 * 1024])?;
+        let mut watcher = Watcher {
+            root: root.to_path_buf(),
+            events,
+            dirs: HashMap::new(),
+        };
+        watcher
+            .watch_tree(root)
+            .map_err(|e| anyhow!("Failed to watch {}: {}", root.display(), e))?;
+        Ok(watcher)
+    }
+
+    /// Watches `dir` and every directory below it. Symlinked directories are
+    /// skipped, as when syncing.
+    fn watch_tree(&mut self, dir: &Path) -> std::io::Result<()> {
+        let mask = WatchMask::CREATE
+            | WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::DELETE_SELF
+            | WatchMask::MOVE_SELF;
+        let wd = self.events.watches().add(dir, mask)?;
+        self.dirs.insert(wd, dir.to_path_buf());
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.watch_tree(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the next inotify event and translates it.
+    async fn next(&mut self) -> Result<Vec<Change>> {
+        let Some(event) = self.events.next().await else {
+            bail!("inotify stopped delivering events");
+        };
+        let event = event?;
+        let mask = event.mask;
+        if mask.contains(EventMask::Q_OVERFLOW) {
+            warn!("Missed file system events, comparing the whole tree again");
+            return Ok(vec![Change::Rescan]);
+        }
+        if mask.contains(EventMask::IGNORED) {
+            self.dirs.remove(&event.wd);
+            return Ok(Vec::new());
+        }
+        let Some(dir) = self.dirs.get(&event.wd).cloned() else {
+            return Ok(Vec::new());
+        };
+        if dir == self.root && mask.intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF) {
+            bail!("{} was removed or moved away", self.root.display());
+        }
+        let Some(name) = event.name else {
+            return Ok(Vec::new());
+        };
+        let path = dir.join(name);
+
+        if mask.contains(EventMask::ISDIR) {
+            if mask.intersects(EventMask::MOVED_FROM | EventMask::MOVED_TO) {
+                return Ok(vec![Change::Rescan]);
+            }
+            if !mask.contains(EventMask::CREATE) {
+                // The files of a deleted directory are reported one by one.
+                return Ok(Vec::new());
+            }
+            // Files may have been written before the new directory was watched.
+            if let Err(e) = self.watch_tree(&path) {
+                warn!("Failed to watch {}: {}", path.display(), e);
+            }
+            let files = walk_files(&path).unwrap_or_default();
+            return Ok(files
+                .iter()
+                .filter_map(|file| self.relative(file))
+                .map(Change::Modified)
+                .collect());
+        }
+
+        let Some(relative) = self.relative(&path) else {
+            return Ok(Vec::new());
+        };
+        if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+            Ok(vec![Change::Removed(relative)])
+        } else {
+            Ok(vec![Change::Modified(relative)])
+        }
+    }
+
+    /// The path relative to the root, for files that are synced at all.
+    fn relative(&self, path: &Path) -> Option<String> {
+        if is_checkpoint(path) {
+            return None;
+        }
+        let relative = relative_key(path.strip_prefix(&self.root).ok()?);
+        if relative.is_none() {
+            warn!("Skipping {}: file name is not valid UTF-8", path.display());
+        }
+        relative
+    }
+}
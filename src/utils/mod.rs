@@ -0,0 +1,99 @@
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::warn;
+use tokio::task::JoinSet;
+
+/// Formats a byte count with binary units, e.g. `1.50 MB`.
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB", "PB", "EB"];
+    let mut index = 0;
+    let mut size = bytes as f64;
+
+    while size >= 1024.0 && index < units.len() - 1 {
+        size /= 1024.0;
+        index += 1;
+    }
+
+    if index == 0 {
+        format!("{} {}", bytes, units[index])
+    } else {
+        format!("{:.2} {}", size, units[index])
+    }
+}
+
+/// Parses a byte size such as `1048576`, `512K`, `8MiB` or `1GB` (binary units).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("unknown size unit in '{}'", s)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// Runs `jobs` as tokio tasks with at most `limit` in flight.
+///
+/// Results are returned in completion order. The first error is returned
+/// immediately and the remaining tasks are cancelled.
+pub async fn run_concurrent<T, F>(limit: usize, jobs: impl IntoIterator<Item = F>) -> Result<Vec<T>>
+where
+    T: Send + 'static,
+    F: Future<Output = Result<T>> + Send + 'static,
+{
+    let mut jobs = jobs.into_iter();
+    let mut tasks = JoinSet::new();
+    let mut results = Vec::new();
+    loop {
+        while tasks.len() < limit.max(1) {
+            match jobs.next() {
+                Some(job) => {
+                    tasks.spawn(job);
+                }
+                None => break,
+            }
+        }
+        match tasks.join_next().await {
+            Some(result) => results.push(result??),
+            None => return Ok(results),
+        }
+    }
+}
+
+/// Recursively lists the regular files under `root`, sorted by path.
+///
+/// Symlinks to files are followed; symlinked directories are skipped so a link
+/// cycle cannot make the walk run forever.
+pub fn walk_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file() || path.is_file() {
+                files.push(path);
+            } else if file_type.is_symlink() {
+                warn!("Skipping symlink {}", path.display());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}